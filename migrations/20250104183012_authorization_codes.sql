@@ -0,0 +1,16 @@
+-- OAuth 2.0 authorization code logic.
+
+CREATE TABLE IF NOT EXISTS authorization_codes (
+  code            TEXT        PRIMARY KEY,
+  user_vanity     TEXT        NOT NULL REFERENCES users(vanity) ON DELETE CASCADE,
+  client_id       TEXT        NOT NULL,
+  redirect_uri    TEXT        NOT NULL,
+  scope           TEXT        NOT NULL DEFAULT '',
+  code_challenge  TEXT        NOT NULL, -- Only S256 is supported.
+  created_at      TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  expire_at       TIMESTAMPTZ NOT NULL DEFAULT NOW() + '10 minutes'
+);
+
+-- Tokens can be delegated to a third-party client with a restricted scope.
+ALTER TABLE tokens ADD COLUMN IF NOT EXISTS client_id TEXT;
+ALTER TABLE tokens ADD COLUMN IF NOT EXISTS scope     TEXT;
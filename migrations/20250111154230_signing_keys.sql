@@ -0,0 +1,13 @@
+-- Server signing keys logic.
+-- Unlike "keys", which holds users public keys, these keys are used to sign
+-- ID Tokens and are published at "/.well-known/jwks.json".
+
+CREATE TABLE IF NOT EXISTS signing_keys (
+  kid           TEXT        PRIMARY KEY,
+  algorithm     TEXT        NOT NULL,
+  private_key   TEXT        NOT NULL, -- PKCS#8 PEM, sealed with AES-GCM when `SIGNING_KEY_SECRET` is set.
+  created_at    TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- OpenID Connect nonce, bound to the ID Token.
+ALTER TABLE authorization_codes ADD COLUMN IF NOT EXISTS nonce TEXT;
@@ -0,0 +1,20 @@
+-- Refresh token logic.
+-- Refresh tokens are rotated on every use; all tokens issued from the same
+-- authorization code share a family, revoked as a whole on reuse.
+
+CREATE TABLE IF NOT EXISTS refresh_tokens (
+  token       TEXT        PRIMARY KEY,
+  family      TEXT        NOT NULL,
+  user_vanity TEXT        NOT NULL REFERENCES users(vanity) ON DELETE CASCADE,
+  client_id   TEXT        NOT NULL,
+  scope       TEXT        NOT NULL DEFAULT '',
+  rotated_at  TIMESTAMPTZ, -- Set once exchanged, kept to detect reuse.
+  created_at  TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  expire_at   TIMESTAMPTZ NOT NULL DEFAULT NOW() + '1 month'
+);
+
+CREATE INDEX IF NOT EXISTS refresh_tokens_family_idx ON refresh_tokens (family);
+
+-- Access tokens issued to a client belong to a refresh token family.
+ALTER TABLE tokens ADD COLUMN IF NOT EXISTS family TEXT;
+CREATE INDEX IF NOT EXISTS tokens_family_idx ON tokens (family);
@@ -0,0 +1,30 @@
+-- OAuth client logic (RFC 7591 and RFC 7592).
+
+CREATE TABLE IF NOT EXISTS clients (
+  client_id                     TEXT        PRIMARY KEY,
+  client_secret                 TEXT, -- Argon2 hash, NULL for public clients.
+  registration_access_token     TEXT        UNIQUE NOT NULL,
+  client_name                   TEXT,
+  redirect_uris                 TEXT[]      NOT NULL,
+  grant_types                   TEXT[]      NOT NULL DEFAULT '{authorization_code}',
+  token_endpoint_auth_method    TEXT        NOT NULL DEFAULT 'client_secret_basic',
+  id_token_signed_response_alg  TEXT        NOT NULL DEFAULT 'RS256',
+  logo_uri                      TEXT,
+  policy_uri                    TEXT,
+  tos_uri                       TEXT,
+  created_at                    TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  updated_at                    TIMESTAMPTZ
+);
+
+SELECT trigger_updated_at('"clients"');
+
+-- Deleting a client revokes everything issued to it.
+ALTER TABLE authorization_codes
+  ADD CONSTRAINT authorization_codes_client_id_fkey
+  FOREIGN KEY (client_id) REFERENCES clients(client_id) ON DELETE CASCADE;
+ALTER TABLE tokens
+  ADD CONSTRAINT tokens_client_id_fkey
+  FOREIGN KEY (client_id) REFERENCES clients(client_id) ON DELETE CASCADE;
+ALTER TABLE refresh_tokens
+  ADD CONSTRAINT refresh_tokens_client_id_fkey
+  FOREIGN KEY (client_id) REFERENCES clients(client_id) ON DELETE CASCADE;
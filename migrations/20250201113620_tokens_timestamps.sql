@@ -0,0 +1,3 @@
+-- Token issuance time is exposed through introspection, day precision is not enough.
+
+ALTER TABLE tokens ALTER COLUMN created_at TYPE TIMESTAMPTZ;
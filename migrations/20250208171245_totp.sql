@@ -0,0 +1,18 @@
+-- TOTP (RFC 6238) multi-factor authentication logic.
+
+CREATE TABLE IF NOT EXISTS totp (
+  user_vanity   TEXT        PRIMARY KEY REFERENCES users(vanity) ON DELETE CASCADE,
+  secret        TEXT        NOT NULL, -- Base32, sealed with AES-GCM when `SIGNING_KEY_SECRET` is set.
+  last_step     BIGINT, -- Last accepted time step, to prevent replays.
+  confirmed_at  TIMESTAMPTZ, -- NULL until a first code is provided.
+  created_at    TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- Pending second login step.
+CREATE TABLE IF NOT EXISTS mfa_challenges (
+  challenge     TEXT        PRIMARY KEY,
+  user_vanity   TEXT        NOT NULL REFERENCES users(vanity) ON DELETE CASCADE,
+  attempts      INT         NOT NULL DEFAULT 0,
+  created_at    TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  expire_at     TIMESTAMPTZ NOT NULL DEFAULT NOW() + '5 minutes'
+);
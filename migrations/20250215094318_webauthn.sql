@@ -0,0 +1,22 @@
+-- WebAuthn credentials logic.
+
+CREATE TABLE IF NOT EXISTS webauthn_credentials (
+  id            TEXT        PRIMARY KEY, -- Base64url encoded credential ID.
+  user_vanity   TEXT        NOT NULL REFERENCES users(vanity) ON DELETE CASCADE,
+  public_key    BYTEA       NOT NULL, -- COSE_Key.
+  sign_count    BIGINT      NOT NULL DEFAULT 0,
+  name          TEXT,
+  created_at    TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  last_used_at  TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS webauthn_credentials_user_vanity_idx ON webauthn_credentials (user_vanity);
+
+-- Pending registration and authentication ceremonies.
+CREATE TABLE IF NOT EXISTS webauthn_challenges (
+  challenge     TEXT        PRIMARY KEY,
+  user_vanity   TEXT        REFERENCES users(vanity) ON DELETE CASCADE, -- NULL for passwordless login.
+  ceremony      TEXT        NOT NULL CHECK (ceremony IN ('registration', 'authentication')),
+  created_at    TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  expire_at     TIMESTAMPTZ NOT NULL DEFAULT NOW() + '5 minutes'
+);
@@ -0,0 +1,11 @@
+-- MFA recovery codes logic.
+
+CREATE TABLE IF NOT EXISTS recovery_codes (
+  id            BIGSERIAL   PRIMARY KEY,
+  user_vanity   TEXT        NOT NULL REFERENCES users(vanity) ON DELETE CASCADE,
+  code          TEXT        NOT NULL, -- Argon2 hash.
+  used_at       TIMESTAMPTZ,
+  created_at    TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS recovery_codes_user_vanity_idx ON recovery_codes (user_vanity);
@@ -0,0 +1,7 @@
+-- Account deletion logic.
+
+-- Invitations outlive accounts that used them.
+ALTER TABLE invite_codes
+  DROP CONSTRAINT IF EXISTS invite_codes_used_by_fkey,
+  ADD CONSTRAINT invite_codes_used_by_fkey
+  FOREIGN KEY (used_by) REFERENCES users(vanity) ON DELETE SET NULL;
@@ -0,0 +1,9 @@
+-- Email verification logic.
+
+CREATE TABLE IF NOT EXISTS email_verifications (
+  token         TEXT        PRIMARY KEY, -- SHA-256 hash.
+  user_vanity   TEXT        NOT NULL REFERENCES users(vanity) ON DELETE CASCADE,
+  email         TEXT        NOT NULL, -- Encrypted address being verified.
+  created_at    TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  expire_at     TIMESTAMPTZ NOT NULL DEFAULT NOW() + '1 day'
+);
@@ -0,0 +1,8 @@
+-- Password reset logic.
+
+CREATE TABLE IF NOT EXISTS password_resets (
+  token         TEXT        PRIMARY KEY, -- SHA-256 hash.
+  user_vanity   TEXT        NOT NULL REFERENCES users(vanity) ON DELETE CASCADE,
+  created_at    TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  expire_at     TIMESTAMPTZ NOT NULL DEFAULT NOW() + '30 minutes'
+);
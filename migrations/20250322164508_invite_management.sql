@@ -0,0 +1,14 @@
+-- Invitation management logic.
+
+-- `used_by` and `used_at` now describe the latest redemption of a code.
+ALTER TABLE invite_codes
+  ADD COLUMN IF NOT EXISTS created_by  TEXT        REFERENCES users(vanity) ON DELETE SET NULL,
+  ADD COLUMN IF NOT EXISTS max_uses    INT         NOT NULL DEFAULT 1,
+  ADD COLUMN IF NOT EXISTS uses        INT         NOT NULL DEFAULT 0,
+  ADD COLUMN IF NOT EXISTS expire_at   TIMESTAMPTZ;
+
+-- Codes redeemed before uses were counted.
+UPDATE invite_codes SET uses = 1 WHERE used_by IS NOT NULL OR used_at IS NOT NULL;
+
+-- Invitations a member can still issue.
+ALTER TABLE users ADD COLUMN IF NOT EXISTS invite_quota INT NOT NULL DEFAULT 0;
@@ -0,0 +1,14 @@
+-- Captcha logic.
+
+CREATE TABLE IF NOT EXISTS captcha_challenges (
+  challenge   TEXT        PRIMARY KEY,
+  created_at  TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  expire_at   TIMESTAMPTZ NOT NULL DEFAULT NOW() + '5 minutes'
+);
+
+-- Keyed by encrypted email, so unknown accounts are tracked alike.
+CREATE TABLE IF NOT EXISTS login_failures (
+  email           TEXT        PRIMARY KEY,
+  failures        INT         NOT NULL DEFAULT 0,
+  last_failure_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,3 @@
+-- Login lockout logic.
+
+ALTER TABLE login_failures ADD COLUMN IF NOT EXISTS locked_until TIMESTAMPTZ;
@@ -0,0 +1,4 @@
+-- Registration access token logic.
+
+-- Tokens are now stored as SHA-256 digests, like other single-use tokens.
+UPDATE clients SET registration_access_token = encode(sha256(convert_to(registration_access_token, 'UTF8')), 'hex');
@@ -0,0 +1,14 @@
+-- Invitation redemption logic.
+
+-- Multi-use codes are redeemed by several users, `used_by` and `used_at` of
+-- `invite_codes` keep the latest one.
+CREATE TABLE IF NOT EXISTS invite_redemptions (
+  code      TEXT        NOT NULL REFERENCES invite_codes(code) ON DELETE CASCADE,
+  vanity    TEXT        NOT NULL REFERENCES users(vanity) ON DELETE CASCADE,
+  used_at   TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (code, vanity)
+);
+
+INSERT INTO invite_redemptions (code, vanity, used_at)
+SELECT code, used_by, COALESCE(used_at, created_at) FROM invite_codes WHERE used_by IS NOT NULL
+ON CONFLICT DO NOTHING;
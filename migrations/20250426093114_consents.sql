@@ -0,0 +1,11 @@
+-- OAuth 2.0 consent logic.
+
+-- Scopes a user approved for a client, so later requests skip the consent screen.
+CREATE TABLE IF NOT EXISTS consents (
+  user_vanity  TEXT        NOT NULL REFERENCES users(vanity) ON DELETE CASCADE,
+  client_id    TEXT        NOT NULL REFERENCES clients(client_id) ON DELETE CASCADE,
+  scope        TEXT[]      NOT NULL DEFAULT '{}',
+  created_at   TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  updated_at   TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (user_vanity, client_id)
+);
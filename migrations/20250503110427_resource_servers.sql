@@ -0,0 +1,5 @@
+-- OAuth resource server logic.
+
+-- Resource servers introspect tokens issued to any client, others only their own.
+-- Set by operators, registration cannot grant it.
+ALTER TABLE clients ADD COLUMN IF NOT EXISTS resource_server BOOLEAN NOT NULL DEFAULT FALSE;
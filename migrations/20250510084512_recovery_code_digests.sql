@@ -0,0 +1,5 @@
+-- MFA recovery code digest logic.
+
+-- Codes are now keyed SHA-256 digests, found directly instead of checking every Argon2 hash.
+-- Argon2 hashes issued before are still accepted until used or regenerated.
+CREATE INDEX IF NOT EXISTS recovery_codes_code_idx ON recovery_codes (code);
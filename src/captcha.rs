@@ -0,0 +1,246 @@
+//! Captcha verification.
+//!
+//! hCaptcha, Turnstile and reCAPTCHA share the same `siteverify` protocol.
+//! Proof of work needs no third party: clients look for a nonce whose hash,
+//! appended to a challenge, starts with enough zero bits.
+
+use async_trait::async_trait;
+use axum::extract::FromRef;
+use rand::distributions::{Alphanumeric, DistString};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Postgres};
+
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::status::Configuration;
+use crate::AppState;
+
+pub const HCAPTCHA_URL: &str = "https://api.hcaptcha.com/siteverify";
+pub const TURNSTILE_URL: &str = "https://challenges.cloudflare.com/turnstile/v0/siteverify";
+pub const RECAPTCHA_URL: &str = "https://www.google.com/recaptcha/api/siteverify";
+/// Leading zero bits required by default, about a second of work in a browser.
+pub const DEFAULT_DIFFICULTY: u32 = 20;
+const CHALLENGE_LENGTH: usize = 32;
+/// Longest wait for a provider, so a slow one cannot hold logins.
+const SITEVERIFY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Errors that may occur while verifying a captcha.
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("`CAPTCHA_SECRET` is required by {0:?}")]
+    MissingSecret(Provider),
+    #[error("HTTP error: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("SQL request failed: {0}")]
+    Sql(#[from] sqlx::Error),
+}
+
+/// Captcha provider, set in `status.json`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Provider {
+    Hcaptcha,
+    Turnstile,
+    Recaptcha,
+    ProofOfWork,
+}
+
+/// Proof-of-work challenge to solve.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Challenge {
+    pub challenge: String,
+    /// Leading zero bits required in `SHA-256(challenge + ":" + nonce)`.
+    pub difficulty: u32,
+}
+
+/// Captcha verifier.
+#[async_trait]
+pub trait CaptchaVerifier: Send + Sync {
+    /// Check a solved captcha, as sent by the client.
+    async fn verify(&self, response: &str) -> Result<bool, Error>;
+
+    /// Issue a challenge, for verifiers not relying on a third party.
+    async fn challenge(&self) -> Result<Option<Challenge>, Error> {
+        Ok(None)
+    }
+}
+
+/// Verifier shared across handlers, if captchas are enabled.
+pub type SharedVerifier = Option<Arc<dyn CaptchaVerifier>>;
+
+impl FromRef<AppState> for SharedVerifier {
+    fn from_ref(app_state: &AppState) -> SharedVerifier {
+        app_state.captcha.clone()
+    }
+}
+
+/// Select verifier from configuration, reading secret from `CAPTCHA_SECRET`.
+pub fn from_config(config: &Configuration, conn: Pool<Postgres>) -> Result<SharedVerifier, Error> {
+    let Some(captcha) = &config.captcha else {
+        return Ok(None);
+    };
+    let secret = || env::var("CAPTCHA_SECRET").map_err(|_| Error::MissingSecret(captcha.provider));
+
+    Ok(Some(match captcha.provider {
+        Provider::Hcaptcha => Arc::new(SiteVerify::new(HCAPTCHA_URL, secret()?)?),
+        Provider::Turnstile => Arc::new(SiteVerify::new(TURNSTILE_URL, secret()?)?),
+        Provider::Recaptcha => Arc::new(SiteVerify::new(RECAPTCHA_URL, secret()?)?),
+        Provider::ProofOfWork => Arc::new(ProofOfWork::new(
+            conn,
+            captcha.difficulty.unwrap_or(DEFAULT_DIFFICULTY),
+        )),
+    }))
+}
+
+#[derive(Deserialize)]
+struct SiteVerifyResponse {
+    success: bool,
+}
+
+/// Verifier calling a third-party `siteverify` endpoint.
+pub struct SiteVerify {
+    client: reqwest::Client,
+    url: String,
+    secret: String,
+}
+
+impl SiteVerify {
+    pub fn new(url: &str, secret: String) -> Result<Self, Error> {
+        Ok(Self {
+            client: reqwest::Client::builder()
+                .timeout(SITEVERIFY_TIMEOUT)
+                .build()?,
+            url: url.to_owned(),
+            secret,
+        })
+    }
+}
+
+#[async_trait]
+impl CaptchaVerifier for SiteVerify {
+    async fn verify(&self, response: &str) -> Result<bool, Error> {
+        let result: SiteVerifyResponse = self
+            .client
+            .post(&self.url)
+            .form(&[("secret", self.secret.as_str()), ("response", response)])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(result.success)
+    }
+}
+
+/// Verifier issuing single-use proof-of-work challenges.
+pub struct ProofOfWork {
+    conn: Pool<Postgres>,
+    difficulty: u32,
+}
+
+impl ProofOfWork {
+    pub fn new(conn: Pool<Postgres>, difficulty: u32) -> Self {
+        Self { conn, difficulty }
+    }
+}
+
+/// Count leading zero bits of a hash.
+fn leading_zeros(hash: &[u8]) -> u32 {
+    let mut zeros = 0;
+    for byte in hash {
+        zeros += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    zeros
+}
+
+#[async_trait]
+impl CaptchaVerifier for ProofOfWork {
+    /// `response` is `challenge:nonce`.
+    async fn verify(&self, response: &str) -> Result<bool, Error> {
+        let Some((challenge, _nonce)) = response.split_once(':') else {
+            return Ok(false);
+        };
+        if leading_zeros(&Sha256::digest(response.as_bytes())) < self.difficulty {
+            return Ok(false);
+        }
+
+        // Challenges are single-use.
+        let result = sqlx::query!(
+            r#"DELETE FROM "captcha_challenges" WHERE challenge = $1 AND expire_at > NOW()"#,
+            challenge,
+        )
+        .execute(&self.conn)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn challenge(&self) -> Result<Option<Challenge>, Error> {
+        let challenge = Alphanumeric.sample_string(&mut OsRng, CHALLENGE_LENGTH);
+
+        // Unsolved challenges would otherwise pile up.
+        sqlx::query!(r#"DELETE FROM "captcha_challenges" WHERE expire_at <= NOW()"#)
+            .execute(&self.conn)
+            .await?;
+        sqlx::query!(
+            r#"INSERT INTO "captcha_challenges" (challenge) values ($1)"#,
+            challenge,
+        )
+        .execute(&self.conn)
+        .await?;
+
+        Ok(Some(Challenge {
+            challenge,
+            difficulty: self.difficulty,
+        }))
+    }
+}
+
+/// Find a nonce solving a proof-of-work challenge.
+#[cfg(test)]
+pub fn solve(challenge: &Challenge) -> String {
+    (0u64..)
+        .map(|nonce| format!("{}:{}", challenge.challenge, nonce))
+        .find(|response| {
+            leading_zeros(&Sha256::digest(response.as_bytes())) >= challenge.difficulty
+        })
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing::post, Form, Json, Router};
+    use std::collections::HashMap;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_site_verify() {
+        // Accepts `ok` when sent with the right secret.
+        let app = Router::new().route(
+            "/siteverify",
+            post(|Form(form): Form<HashMap<String, String>>| async move {
+                let success = form.get("secret").map(String::as_str) == Some("secret")
+                    && form.get("response").map(String::as_str) == Some("ok");
+                Json(serde_json::json!({ "success": success }))
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/siteverify", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let verifier = SiteVerify::new(&url, "secret".into()).unwrap();
+        assert!(verifier.verify("ok").await.unwrap());
+        assert!(!verifier.verify("bot").await.unwrap());
+
+        assert_eq!(leading_zeros(&[0, 0b0001_0000, 0]), 11);
+    }
+}
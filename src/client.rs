@@ -0,0 +1,56 @@
+//! OAuth client representation.
+use sqlx::{Pool, Postgres};
+
+/// Database OAuth client representation.
+#[derive(Debug, Default, PartialEq)]
+pub struct Client {
+    pub client_id: String,
+    pub(crate) client_secret: Option<String>,
+    /// Digest of the registration access token.
+    pub(crate) registration_access_token: String,
+    pub client_name: Option<String>,
+    pub redirect_uris: Vec<String>,
+    pub grant_types: Vec<String>,
+    pub token_endpoint_auth_method: String,
+    pub id_token_signed_response_alg: String,
+    pub logo_uri: Option<String>,
+    pub policy_uri: Option<String>,
+    pub tos_uri: Option<String>,
+    /// Whether the client may introspect tokens issued to other clients.
+    pub resource_server: bool,
+    /// Registration time, as a UNIX timestamp.
+    pub issued_at: i64,
+}
+
+impl Client {
+    /// Get data on a client.
+    pub async fn get(conn: &Pool<Postgres>, client_id: &str) -> Result<Self, sqlx::Error> {
+        sqlx::query_as!(
+            Client,
+            r#"SELECT client_id, client_secret, registration_access_token, client_name, redirect_uris,
+            grant_types, token_endpoint_auth_method, id_token_signed_response_alg, logo_uri, policy_uri,
+            tos_uri, resource_server, EXTRACT(EPOCH FROM created_at)::BIGINT AS "issued_at!"
+            FROM clients WHERE client_id = $1"#,
+            client_id,
+        )
+        .fetch_one(conn)
+        .await
+    }
+
+    /// Whether the client cannot keep a secret, such as a single-page or mobile app.
+    pub fn is_public(&self) -> bool {
+        self.token_endpoint_auth_method == "none"
+    }
+
+    /// Whether the client registered `grant_type`.
+    pub fn allows_grant(&self, grant_type: &str) -> bool {
+        self.grant_types.iter().any(|grant| grant == grant_type)
+    }
+
+    /// Check the client secret against its stored hash.
+    pub fn verify_secret(&self, secret: &str) -> bool {
+        self.client_secret
+            .as_deref()
+            .is_some_and(|hash| crate::crypto::verify_password(secret.as_bytes(), hash))
+    }
+}
@@ -1,22 +1,187 @@
 //! Cryptogragic logic.
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Algorithm, Argon2, Params, Version,
+};
 use fpe::ff1::{FlexibleNumeralString, Operations, FF1};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use std::sync::OnceLock;
 
 const RADIX: u32 = 256;
 
+static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+
+/// FPE cipher keyed by `AES_KEY`, if set.
+fn email_cipher() -> Option<FF1<aes::Aes256>> {
+    std::env::var("AES_KEY")
+        .ok()
+        .and_then(|key| hex::decode(&key).ok())
+        .and_then(|key| FF1::<aes::Aes256>::new(&key, RADIX).ok())
+}
+
+fn encrypt(ff: &FF1<aes::Aes256>, data: &str) -> Option<String> {
+    let email: Vec<u16> = data.encode_utf16().collect();
+    let email_length = email.len();
+
+    ff.encrypt(&[], &FlexibleNumeralString::from(email))
+        .ok()
+        .map(|encrypted| hex::encode(encrypted.to_be_bytes(RADIX, email_length)))
+}
+
+fn decrypt(ff: &FF1<aes::Aes256>, data: &str) -> Option<String> {
+    let email: Vec<u16> = hex::decode(data).ok()?.into_iter().map(u16::from).collect();
+
+    ff.decrypt(&[], &FlexibleNumeralString::from(email))
+        .ok()
+        .and_then(|decrypted| String::from_utf16(&Vec::from(decrypted)).ok())
+}
+
 /// Encrypt email using FPE.
 #[inline(always)]
 pub fn email_encryption(data: String) -> String {
-    std::env::var("AES_KEY")
+    email_cipher()
+        .and_then(|ff| encrypt(&ff, &data))
+        .unwrap_or(data)
+}
+
+/// Decrypt email encrypted by [`email_encryption`].
+#[inline(always)]
+pub fn email_decryption(data: String) -> String {
+    email_cipher()
+        .and_then(|ff| decrypt(&ff, &data))
+        .unwrap_or(data)
+}
+
+/// Argon2 cost of new hashes, read from `ARGON2_M_COST` (KiB), `ARGON2_T_COST` and `ARGON2_P_COST`.
+pub fn password_params() -> Result<Params, argon2::Error> {
+    let var = |name, default| {
+        std::env::var(name)
             .ok()
-            .and_then(|key| hex::decode(&key).ok())
-            .and_then(|key| FF1::<aes::Aes256>::new(&key, RADIX).ok())
-            .and_then(|ff| {
-                let email: Vec<u16> = data.encode_utf16().collect();
-                let email_length = email.len();
-
-                ff.encrypt(&[], &FlexibleNumeralString::from(email))
-                    .ok()
-                    .map(|encrypted| hex::encode(encrypted.to_be_bytes(RADIX, email_length)))
-            })
-            .unwrap_or(data)
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(default)
+    };
+
+    Params::new(
+        var("ARGON2_M_COST", Params::DEFAULT_M_COST * 4),
+        var("ARGON2_T_COST", 6),
+        var("ARGON2_P_COST", Params::DEFAULT_P_COST),
+        None,
+    )
+}
+
+/// Hash a password, or any other secret, using Argon2id.
+pub fn hash_password(password: &[u8]) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, password_params()?);
+
+    Ok(argon2.hash_password(password, &salt)?.to_string())
+}
+
+/// Check a password against an Argon2 hash.
+pub fn verify_password(password: &[u8], hash: &str) -> bool {
+    PasswordHash::new(hash)
+        .and_then(|hash| Argon2::default().verify_password(password, &hash))
+        .is_ok()
+}
+
+/// Run Argon2 work on the blocking pool, as a hash at [`password_params`] cost
+/// would otherwise stall an async worker for its whole duration.
+pub async fn blocking<T: Send + 'static>(work: impl FnOnce() -> T + Send + 'static) -> T {
+    match tokio::task::spawn_blocking(work).await {
+        Ok(output) => output,
+        // Same as running it inline.
+        Err(err) => std::panic::resume_unwind(err.into_panic()),
+    }
+}
+
+/// Compute the throwaway hash of [`dummy_verify_password`], at startup.
+///
+/// Fails when [`password_params`] are invalid, as every new hash would.
+pub fn init_dummy_hash() -> Result<(), argon2::password_hash::Error> {
+    let hash = hash_password(b"dummy password")?;
+    let _ = DUMMY_HASH.set(hash);
+
+    Ok(())
+}
+
+/// Check a password against a throwaway hash, as long as [`verify_password`] takes.
+///
+/// Used for unknown users, so response time does not reveal whether an account exists.
+pub fn dummy_verify_password(password: &[u8]) {
+    // Only computed here when [`init_dummy_hash`] was not called, such as in tests.
+    let hash = DUMMY_HASH.get_or_init(|| {
+        hash_password(b"dummy password").expect("Argon2 parameters are checked at startup")
+    });
+    let _ = verify_password(password, hash);
+}
+
+/// Check whether a hash is weaker than [`password_params`], and should be replaced.
+pub fn needs_rehash(hash: &str) -> bool {
+    let (Ok(hash), Ok(policy)) = (PasswordHash::new(hash), password_params()) else {
+        return false;
+    };
+    if hash.algorithm != Algorithm::Argon2id.ident()
+        || hash.version != Some(Version::V0x13.into())
+    {
+        return true;
+    }
+
+    Params::try_from(&hash).map_or(true, |params| {
+        params.m_cost() < policy.m_cost()
+            || params.t_cost() < policy.t_cost()
+            || params.p_cost() < policy.p_cost()
+    })
+}
+
+/// Hash a random single-use token before storing it.
+///
+/// Tokens have enough entropy for a fast digest, unlike passwords.
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Hash a short random secret, such as a recovery code, keyed by `AES_KEY` if set.
+///
+/// Secrets too short to resist brute force from a database dump still stay out of
+/// reach without the key, while being found by digest, unlike Argon2 hashes.
+pub fn keyed_hash(data: &str) -> String {
+    let key = std::env::var("AES_KEY")
+        .ok()
+        .and_then(|key| hex::decode(key).ok())
+        .unwrap_or_default();
+    let mut mac = Hmac::<Sha256>::new_from_slice(&key).expect("HMAC takes keys of any size");
+    mac.update(data.as_bytes());
+
+    hex::encode(mac.finalize().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_email_decryption() {
+        // Environment is shared by every test, so the key is not read from it.
+        let ff = FF1::<aes::Aes256>::new(&[7; 32], RADIX).unwrap();
+        let email = "test@gravitalia.com";
+
+        let encrypted = encrypt(&ff, email).unwrap();
+        assert_ne!(encrypted, email);
+        assert_eq!(decrypt(&ff, &encrypted).as_deref(), Some(email));
+    }
+
+    #[test]
+    fn test_needs_rehash() {
+        let salt = SaltString::generate(&mut OsRng);
+        let weak = Argon2::default()
+            .hash_password(b"Password1234", &salt)
+            .unwrap()
+            .to_string();
+
+        assert!(verify_password(b"Password1234", &weak));
+        assert!(needs_rehash(&weak));
+        assert!(!needs_rehash(&hash_password(b"Password1234").unwrap()));
+    }
 }
@@ -1,17 +1,26 @@
 #[forbid(unsafe_code)]
 #[deny(missing_docs, unused_mut)]
 mod crypto;
+mod captcha;
+mod client;
 mod database;
+mod jwt;
+mod mail;
 mod metrics;
+mod oauth;
+mod password;
+mod ratelimit;
 mod router;
 mod status;
 mod user;
+mod webauthn;
 mod well_known;
 
 use axum::{
+    handler::Handler,
     http::{header, Method},
     middleware,
-    routing::{get, post},
+    routing::{get, post, put},
     Router,
 };
 use tower_http::cors::{Any, CorsLayer};
@@ -22,32 +31,135 @@ use well_known::well_known;
 
 use std::env;
 use std::future::ready;
+use std::net::SocketAddr;
 use std::process;
+use std::sync::Arc;
 use std::time::Duration;
 
 #[derive(Clone)]
 pub struct AppState {
     pub config: status::Configuration,
     pub db: database::Database,
+    pub keys: jwt::KeyManager,
+    pub mailer: mail::SharedMailer,
+    pub captcha: captcha::SharedVerifier,
 }
 
 /// Create router.
 pub fn app(state: AppState) -> Router {
+    // limit authentication attempts.
+    let limiter = ratelimit::RateLimitLayer::new(
+        state.config.rate_limits.clone(),
+        Arc::new(ratelimit::Memory::default()),
+    );
+
     Router::new()
         // `GET /status.json` goes to `status`.
         .route("/status.json", get(router::status::status))
         // `POST /login` goes to `login`.
-        .route("/login", post(router::login::login))
+        .route("/login", post(router::login::login).layer(limiter.clone()))
+        // `POST /login/mfa` goes to `login::mfa`.
+        .route("/login/mfa", post(router::login::mfa).layer(limiter.clone()))
+        // `POST /login/webauthn/options` goes to `webauthn::login_options`.
+        .route(
+            "/login/webauthn/options",
+            post(router::webauthn::login_options).layer(limiter.clone()),
+        )
+        // `POST /login/webauthn` goes to `webauthn::login`.
+        .route("/login/webauthn", post(router::webauthn::login).layer(limiter.clone()))
+        // `POST /logout` goes to `logout`.
+        .route("/logout", post(router::logout::logout))
         // `POST /create` goes to `create`.
-        .route("/create", post(router::create::create))
+        .route("/create", post(router::create::create).layer(limiter.clone()))
+        // `POST /captcha/challenge` goes to `captcha::challenge`.
+        .route(
+            "/captcha/challenge",
+            post(router::captcha::challenge).layer(limiter.clone()),
+        )
+        // `POST /password/forgot` goes to `password::forgot`.
+        .route("/password/forgot", post(router::password::forgot).layer(limiter.clone()))
+        // `POST /password/reset` goes to `password::reset`.
+        .route("/password/reset", post(router::password::reset).layer(limiter.clone()))
+        // `GET /users/@me` goes to `users::me`.
+        // `PATCH /users/@me` goes to `users::update_me`.
+        // `DELETE /users/@me` goes to `users::delete_me`.
+        .route(
+            "/users/@me",
+            get(router::users::me)
+                .patch(router::users::update_me.layer(limiter.clone()))
+                .delete(router::users::delete_me),
+        )
+        // `GET /users/{vanity}` goes to `users::get`.
+        .route("/users/{vanity}", get(router::users::get))
+        // `GET /users/{vanity}/inbox` goes to `users::inbox`.
+        .route("/users/{vanity}/inbox", get(router::users::inbox))
+        // `GET /users/{vanity}/outbox` goes to `users::outbox`.
+        .route("/users/{vanity}/outbox", get(router::users::outbox))
+        // `POST /users/@me/email/verification` goes to `email::resend`.
+        .route(
+            "/users/@me/email/verification",
+            post(router::email::resend).layer(limiter.clone()),
+        )
+        // `POST /users/@me/email/verify` goes to `email::verify`.
+        .route(
+            "/users/@me/email/verify",
+            post(router::email::verify).layer(limiter.clone()),
+        )
+        // `POST /users/@me/mfa/totp` goes to `mfa::enroll_totp`.
+        .route("/users/@me/mfa/totp", post(router::mfa::enroll_totp))
+        // `POST /users/@me/mfa/totp/confirm` goes to `mfa::confirm_totp`.
+        .route(
+            "/users/@me/mfa/totp/confirm",
+            post(router::mfa::confirm_totp).layer(limiter.clone()),
+        )
+        // `POST /users/@me/mfa/recovery-codes` goes to `mfa::regenerate_recovery_codes`.
+        .route(
+            "/users/@me/mfa/recovery-codes",
+            post(router::mfa::regenerate_recovery_codes),
+        )
+        // `GET /users/@me/invites` goes to `invites::mine`.
+        // `POST /users/@me/invites` goes to `invites::create_mine`.
+        .route(
+            "/users/@me/invites",
+            get(router::invites::mine).post(router::invites::create_mine),
+        )
+        // `POST /users/@me/webauthn/register/options` goes to `webauthn::register_options`.
+        .route(
+            "/users/@me/webauthn/register/options",
+            post(router::webauthn::register_options),
+        )
+        // `POST /users/@me/webauthn/register` goes to `webauthn::register`.
+        .route(
+            "/users/@me/webauthn/register",
+            post(router::webauthn::register),
+        )
+        // `GET /admin/invites` goes to `invites::list`.
+        // `POST /admin/invites` goes to `invites::create_batch`.
+        .route(
+            "/admin/invites",
+            get(router::invites::list).post(router::invites::create_batch),
+        )
+        // `PUT /admin/users/{vanity}/invite-quota` goes to `invites::set_quota`.
+        .route(
+            "/admin/users/{vanity}/invite-quota",
+            put(router::invites::set_quota),
+        )
         .with_state(state.clone())
-        .nest("/.well-known", well_known(state))
+        .nest("/.well-known", well_known(state.clone()))
+        .nest("/oauth", oauth::oauth(state, limiter))
         .layer(TraceLayer::new_for_http())
         .route_layer(middleware::from_fn(metrics::track_metrics))
         .route_layer(
             CorsLayer::new()
                 .allow_origin(Any)
-                .allow_methods([Method::GET, Method::POST, Method::OPTIONS, Method::PATCH])
+                .allow_methods([
+                    Method::GET,
+                    Method::POST,
+                    Method::PUT,
+                    Method::DELETE,
+                    Method::OPTIONS,
+                    Method::PATCH,
+                ])
                 .vary([header::AUTHORIZATION]),
         )
 }
@@ -81,12 +193,33 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // load configuration and let it on memory.
     // init databases connection.
+    let db = database::Database::new(
+        &env::var("POSTGRES_URL").unwrap_or_else(|_| database::DEFAULT_PG_URL.into()),
+    )
+    .await?;
+    // hard-delete expired accounts every hour.
+    tokio::spawn(user::purge_periodically(
+        db.postgres.clone(),
+        Duration::from_secs(60 * 60),
+    ));
+    let config = status::Configuration::read(None)?;
+    // load breached passwords, if any.
+    password::load_from_env()?;
+    // hash once, so unknown users cost as much as known ones from the first login.
+    crypto::init_dummy_hash().map_err(|err| err.to_string())?;
+    // load signing keys, rotating them if needed.
+    let keys = jwt::KeyManager::new(&db.postgres).await?;
+    // check again every hour, as keys outdate while running.
+    tokio::spawn(
+        keys.clone()
+            .rotate_periodically(db.postgres.clone(), Duration::from_secs(60 * 60)),
+    );
     let state = AppState {
-        config: status::Configuration::read(None)?,
-        db: database::Database::new(
-            &env::var("POSTGRES_URL").unwrap_or_else(|_| database::DEFAULT_PG_URL.into()),
-        )
-        .await?,
+        keys,
+        mailer: mail::from_env()?,
+        captcha: captcha::from_config(&config, db.postgres.clone())?,
+        config,
+        db,
     };
 
     // build our application with a route.
@@ -101,7 +234,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     ))
     .await?;
     tracing::info!("listening on {}", listener.local_addr()?);
-    axum::serve(listener, app).await?;
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
 
     Ok(())
 }
@@ -0,0 +1,585 @@
+//! Authorization endpoint (RFC 6749 section 3.1).
+//!
+//! Path: /oauth/authorize?response_type=code&client_id=ID&redirect_uri=URI&code_challenge=CHALLENGE&code_challenge_method=S256
+//!
+//! Browsers are authenticated by the session cookie set on login. Without one,
+//! they are sent to the login page, which brings them back once signed in.
+//! Scopes not yet approved for the client go through the consent page first,
+//! which posts the user's decision back to this endpoint.
+
+use axum::extract::{OriginalUri, Query, State};
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Redirect, Response};
+use axum::Json;
+use rand::distributions::{Alphanumeric, DistString};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::{client::Client, database::Database, status::Configuration, user::User};
+
+use super::Error;
+
+const CODE_LENGTH: usize = 32;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Params {
+    response_type: String,
+    client_id: String,
+    redirect_uri: String,
+    scope: Option<String>,
+    state: Option<String>,
+    code_challenge: Option<String>,
+    code_challenge_method: Option<String>,
+    nonce: Option<String>,
+}
+
+/// Decision of the user on the consent page.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Consent {
+    #[serde(flatten)]
+    params: Params,
+    approve: bool,
+}
+
+/// Where the consent page sends the user next.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Decision {
+    redirect_uri: String,
+}
+
+impl Params {
+    /// Parameters of the request, to pass it along.
+    fn pairs(&self) -> impl Iterator<Item = (&str, &str)> {
+        [
+            ("response_type", Some(&self.response_type)),
+            ("client_id", Some(&self.client_id)),
+            ("redirect_uri", Some(&self.redirect_uri)),
+            ("scope", self.scope.as_ref()),
+            ("state", self.state.as_ref()),
+            ("code_challenge", self.code_challenge.as_ref()),
+            ("code_challenge_method", self.code_challenge_method.as_ref()),
+            ("nonce", self.nonce.as_ref()),
+        ]
+        .into_iter()
+        .filter_map(|(key, value)| Some((key, value?.as_str())))
+    }
+}
+
+/// Send the user to a first-party page, which comes back to this request afterwards.
+fn page_redirect<'a>(
+    page: Option<&str>,
+    pairs: impl IntoIterator<Item = (&'a str, &'a str)>,
+) -> Option<Response> {
+    let mut url = Url::parse(page?).ok()?;
+    url.query_pairs_mut().extend_pairs(pairs);
+
+    Some(Redirect::to(url.as_str()).into_response())
+}
+
+/// Find the client and check the redirection URI, whose errors must not be redirected.
+async fn client(db: &Database, params: &Params) -> Result<(Client, Url), Error> {
+    let redirect_uri = Url::parse(&params.redirect_uri)
+        .map_err(|_| Error::InvalidRequest("Invalid `redirect_uri`.".into()))?;
+    let client = match Client::get(&db.postgres, &params.client_id).await {
+        Ok(client) => client,
+        Err(sqlx::Error::RowNotFound) => {
+            return Err(Error::InvalidRequest("Unknown `client_id`.".into()))
+        }
+        Err(err) => return Err(err.into()),
+    };
+    if !client.redirect_uris.contains(&params.redirect_uri) {
+        return Err(Error::InvalidRequest(
+            "`redirect_uri` is not registered for this client.".into(),
+        ));
+    }
+
+    Ok((client, redirect_uri))
+}
+
+/// Send the result back to the client through its redirection URI.
+fn respond(
+    mut redirect_uri: Url,
+    result: Result<String, Error>,
+    params: &Params,
+) -> Result<Url, Error> {
+    let pairs = match result {
+        Ok(code) => vec![("code", code)],
+        Err(err @ (Error::Sql(_) | Error::Jwt(_) | Error::Internal(_))) => return Err(err),
+        Err(err) => vec![
+            ("error", err.to_string()),
+            ("error_description", err.description()),
+        ],
+    };
+
+    redirect_uri
+        .query_pairs_mut()
+        .extend_pairs(pairs)
+        .extend_pairs(params.state.as_ref().map(|state| ("state", state)));
+
+    Ok(redirect_uri)
+}
+
+pub async fn handler(
+    State(db): State<Database>,
+    State(config): State<Configuration>,
+    headers: HeaderMap,
+    uri: OriginalUri,
+    Query(params): Query<Params>,
+) -> Result<Response, Error> {
+    let (client, redirect_uri) = client(&db, &params).await?;
+
+    let login = || {
+        let request = Url::parse(&config.url)
+            .and_then(|url| url.join(&uri.to_string()))
+            .map(String::from)
+            .unwrap_or_default();
+        page_redirect(
+            config.login_url.as_deref(),
+            [("redirect", request.as_str())],
+        )
+        .ok_or_else(|| Error::AccessDenied("User must be authenticated.".into()))
+    };
+    let Some(token) = super::bearer(&headers).or_else(|| super::session(&headers)) else {
+        return login();
+    };
+    let user = match User::from_token(&db.postgres, token).await {
+        Ok(user) => user,
+        Err(sqlx::Error::RowNotFound) => return login(),
+        Err(err) => return Err(err.into()),
+    };
+
+    let result = match validate(&client, &params) {
+        Ok(scopes) if consented(&db, &user, &client, &scopes).await? => {
+            issue(&db, &client, &user, &params).await
+        }
+        Ok(_) => {
+            let about = [
+                ("client_name", client.client_name.as_deref()),
+                ("logo_uri", client.logo_uri.as_deref()),
+                ("policy_uri", client.policy_uri.as_deref()),
+                ("tos_uri", client.tos_uri.as_deref()),
+            ]
+            .into_iter()
+            .filter_map(|(key, value)| Some((key, value?)));
+            match page_redirect(config.consent_url.as_deref(), params.pairs().chain(about)) {
+                Some(response) => return Ok(response),
+                None => Err(Error::AccessDenied("User consent is required.".into())),
+            }
+        }
+        Err(err) => Err(err),
+    };
+
+    Ok(Redirect::to(respond(redirect_uri, result, &params)?.as_str()).into_response())
+}
+
+/// Record the decision of the user, then finish the authorization request.
+///
+/// Only the first-party consent page holds a bearer token: accepting the session
+/// cookie here would let any website approve requests on behalf of its visitors.
+pub async fn consent(
+    State(db): State<Database>,
+    headers: HeaderMap,
+    Json(body): Json<Consent>,
+) -> Result<Json<Decision>, Error> {
+    let params = &body.params;
+    let (client, redirect_uri) = client(&db, params).await?;
+
+    let token = super::bearer(&headers)
+        .ok_or_else(|| Error::AccessDenied("User must be authenticated.".into()))?;
+    let user = User::from_token(&db.postgres, token)
+        .await
+        .map_err(|err| match err {
+            sqlx::Error::RowNotFound => Error::AccessDenied("User must be authenticated.".into()),
+            err => err.into(),
+        })?;
+
+    let result = match validate(&client, params) {
+        Ok(_) if !body.approve => Err(Error::AccessDenied("User denied the request.".into())),
+        Ok(scopes) => {
+            remember(&db, &user, &client, &scopes).await?;
+            issue(&db, &client, &user, params).await
+        }
+        Err(err) => Err(err),
+    };
+
+    Ok(Json(Decision {
+        redirect_uri: respond(redirect_uri, result, params)?.into(),
+    }))
+}
+
+/// Validate the authorization request, returning the requested scopes.
+fn validate<'a>(client: &Client, params: &'a Params) -> Result<Vec<&'a str>, Error> {
+    if !client.allows_grant("authorization_code") {
+        return Err(Error::UnauthorizedClient(
+            "Client did not register the `authorization_code` grant type.".into(),
+        ));
+    }
+    if params.response_type != "code" {
+        return Err(Error::UnsupportedResponseType(
+            "Only `code` response type is supported.".into(),
+        ));
+    }
+
+    let code_challenge = params
+        .code_challenge
+        .as_deref()
+        .ok_or_else(|| Error::InvalidRequest("PKCE `code_challenge` is required.".into()))?;
+    if params.code_challenge_method.as_deref() != Some("S256") {
+        return Err(Error::InvalidRequest(
+            "Transform algorithm not supported, use `S256`.".into(),
+        ));
+    }
+    if !super::is_valid_pkce(code_challenge) {
+        return Err(Error::InvalidRequest("Invalid `code_challenge`.".into()));
+    }
+
+    let scopes: Vec<&str> = params
+        .scope
+        .as_deref()
+        .unwrap_or_default()
+        .split(' ')
+        .filter(|scope| !scope.is_empty())
+        .collect();
+    if let Some(scope) = scopes.iter().find(|scope| !super::SCOPES.contains(scope)) {
+        return Err(Error::InvalidScope(format!("Unknown scope `{}`.", scope)));
+    }
+
+    Ok(scopes)
+}
+
+/// Check whether the user already approved these scopes for the client.
+async fn consented(
+    db: &Database,
+    user: &User,
+    client: &Client,
+    scopes: &[&str],
+) -> Result<bool, Error> {
+    let scopes: Vec<String> = scopes.iter().map(|scope| scope.to_string()).collect();
+
+    Ok(sqlx::query_scalar!(
+        r#"SELECT $3::TEXT[] <@ scope AS "covered!" FROM "consents"
+        WHERE user_vanity = $1 AND client_id = $2"#,
+        user.vanity,
+        client.client_id,
+        &scopes,
+    )
+    .fetch_optional(&db.postgres)
+    .await?
+    .unwrap_or(false))
+}
+
+/// Remember approved scopes, on top of those approved before.
+async fn remember(
+    db: &Database,
+    user: &User,
+    client: &Client,
+    scopes: &[&str],
+) -> Result<(), Error> {
+    let scopes: Vec<String> = scopes.iter().map(|scope| scope.to_string()).collect();
+
+    sqlx::query!(
+        r#"INSERT INTO "consents" (user_vanity, client_id, scope) values ($1, $2, $3)
+        ON CONFLICT (user_vanity, client_id) DO UPDATE
+        SET scope = ARRAY(SELECT DISTINCT UNNEST(consents.scope || EXCLUDED.scope)),
+        updated_at = NOW()"#,
+        user.vanity,
+        client.client_id,
+        &scopes,
+    )
+    .execute(&db.postgres)
+    .await?;
+
+    Ok(())
+}
+
+/// Issue an authorization code for a validated request.
+async fn issue(
+    db: &Database,
+    client: &Client,
+    user: &User,
+    params: &Params,
+) -> Result<String, Error> {
+    let code = Alphanumeric.sample_string(&mut OsRng, CODE_LENGTH);
+
+    sqlx::query!(
+        r#"INSERT INTO "authorization_codes" (code, user_vanity, client_id, redirect_uri, scope, code_challenge, nonce)
+        values ($1, $2, $3, $4, $5, $6, $7)"#,
+        code,
+        user.vanity,
+        client.client_id,
+        params.redirect_uri,
+        params.scope.as_deref().unwrap_or_default(),
+        params.code_challenge,
+        params.nonce,
+    )
+    .execute(&db.postgres)
+    .await?;
+
+    Ok(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use axum::{
+        body::Body as RequestBody,
+        body::Bytes,
+        http::{self, Request, StatusCode},
+    };
+    use http_body_util::BodyExt;
+    use sqlx::{Pool, Postgres};
+    use tower::ServiceExt;
+
+    #[sqlx::test]
+    async fn test_authorize_handler(pool: Pool<Postgres>) {
+        sqlx::query!(
+            r#"INSERT INTO "users" (vanity, username, email, password) values ($1, $2, $3, $4)"#,
+            "user",
+            "User",
+            "test@gravitalia.com",
+            "",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query!(
+            r#"INSERT INTO "clients" (client_id, registration_access_token, redirect_uris, token_endpoint_auth_method)
+            values ($1, $2, $3, $4)"#,
+            "app",
+            "registration",
+            &["https://app.gravitalia.com/callback".to_string()],
+            "none",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let token = user::User::default()
+            .with_vanity("user".into())
+            .get(&pool)
+            .await
+            .unwrap()
+            .generate_token(&pool)
+            .await
+            .unwrap();
+
+        let mut config = status::Configuration::default();
+        config.url = "https://account.gravitalia.com".into();
+        config.login_url = Some("https://account.gravitalia.com/signin".into());
+        let state = AppState {
+            db: database::Database { postgres: pool },
+            config,
+            keys: jwt::KeyManager::default(),
+            mailer: std::sync::Arc::new(mail::Stdout),
+            captcha: None,
+        };
+        let app = app(state);
+
+        const URI: &str = "/oauth/authorize?response_type=code&client_id=app&redirect_uri=https://app.gravitalia.com/callback&state=xyz";
+
+        // Unauthenticated browsers sign in first.
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::GET)
+                    .uri(URI)
+                    .body(RequestBody::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+
+        let location = response.headers()[http::header::LOCATION].to_str().unwrap();
+        let location = url::Url::parse(location).unwrap();
+        assert_eq!(location.path(), "/signin");
+        let redirect = location
+            .query_pairs()
+            .find(|(key, _)| key == "redirect")
+            .unwrap()
+            .1;
+        assert_eq!(redirect, format!("https://account.gravitalia.com{}", URI));
+
+        // The session cookie authenticates them afterwards.
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::GET)
+                    .uri(URI)
+                    .header(
+                        http::header::COOKIE,
+                        format!("theme=dark; {}={}", oauth::SESSION_COOKIE, token),
+                    )
+                    .body(RequestBody::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+
+        let location = response.headers()[http::header::LOCATION].to_str().unwrap();
+        assert!(location.starts_with("https://app.gravitalia.com/callback?error=invalid_request"));
+
+        // PKCE is mandatory.
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::GET)
+                    .uri("/oauth/authorize?response_type=code&client_id=app&redirect_uri=https://app.gravitalia.com/callback&state=xyz")
+                    .header(http::header::AUTHORIZATION, format!("Bearer {}", token))
+                    .body(RequestBody::empty())
+                    .unwrap()
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+
+        let location = response.headers()[http::header::LOCATION].to_str().unwrap();
+        assert!(location.starts_with("https://app.gravitalia.com/callback?error=invalid_request"));
+        assert!(location.ends_with("&state=xyz"));
+    }
+
+    #[sqlx::test]
+    async fn test_consent(pool: Pool<Postgres>) {
+        sqlx::query!(
+            r#"INSERT INTO "users" (vanity, username, email, password) values ($1, $2, $3, $4)"#,
+            "user",
+            "User",
+            "test@gravitalia.com",
+            "",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query!(
+            r#"INSERT INTO "clients" (client_id, registration_access_token, client_name, redirect_uris, token_endpoint_auth_method)
+            values ($1, $2, $3, $4, $5)"#,
+            "app",
+            "registration",
+            "App",
+            &["https://app.gravitalia.com/callback".to_string()],
+            "none",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let token = user::User::default()
+            .with_vanity("user".into())
+            .get(&pool)
+            .await
+            .unwrap()
+            .generate_token(&pool)
+            .await
+            .unwrap();
+
+        let mut config = status::Configuration::default();
+        config.consent_url = Some("https://account.gravitalia.com/consent".into());
+        let state = AppState {
+            db: database::Database { postgres: pool },
+            config,
+            keys: jwt::KeyManager::default(),
+            mailer: std::sync::Arc::new(mail::Stdout),
+            captcha: None,
+        };
+        let app = app(state);
+
+        let params = serde_json::json!({
+            "response_type": "code",
+            "client_id": "app",
+            "redirect_uri": "https://app.gravitalia.com/callback",
+            "scope": "openid email",
+            "state": "xyz",
+            "code_challenge": oauth::pkce_challenge(&"a".repeat(43)),
+            "code_challenge_method": "S256",
+        });
+        let authorize = |params: &serde_json::Value| {
+            let mut uri = url::Url::parse("https://localhost/oauth/authorize").unwrap();
+            for (key, value) in params.as_object().unwrap() {
+                uri.query_pairs_mut()
+                    .append_pair(key, value.as_str().unwrap());
+            }
+            Request::builder()
+                .method(http::Method::GET)
+                .uri(&uri[url::Position::BeforePath..])
+                .header(
+                    http::header::COOKIE,
+                    format!("{}={}", oauth::SESSION_COOKIE, token),
+                )
+                .body(RequestBody::empty())
+                .unwrap()
+        };
+        let consent = |approve: bool, bearer: bool| {
+            let mut body = params.clone();
+            body["approve"] = approve.into();
+            let request = Request::builder()
+                .method(http::Method::POST)
+                .uri("/oauth/authorize")
+                .header(http::header::CONTENT_TYPE, "application/json");
+            let request = if bearer {
+                request.header(http::header::AUTHORIZATION, format!("Bearer {}", token))
+            } else {
+                request.header(
+                    http::header::COOKIE,
+                    format!("{}={}", oauth::SESSION_COOKIE, token),
+                )
+            };
+            request.body(RequestBody::from(body.to_string())).unwrap()
+        };
+        let decision = |body: Bytes| {
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            url::Url::parse(body["redirect_uri"].as_str().unwrap()).unwrap()
+        };
+
+        // Scopes must be known.
+        let mut unknown = params.clone();
+        unknown["scope"] = "openid admin".into();
+        let response = app.clone().oneshot(authorize(&unknown)).await.unwrap();
+        let location = response.headers()[http::header::LOCATION].to_str().unwrap();
+        assert!(location.starts_with("https://app.gravitalia.com/callback?error=invalid_scope"));
+
+        // First requests go through the consent page.
+        let response = app.clone().oneshot(authorize(&params)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        let location = response.headers()[http::header::LOCATION].to_str().unwrap();
+        let location = url::Url::parse(location).unwrap();
+        assert_eq!(location.path(), "/consent");
+        assert!(location
+            .query_pairs()
+            .any(|(key, value)| key == "client_name" && value == "App"));
+
+        // Other websites cannot approve with the session cookie.
+        let response = app.clone().oneshot(consent(true, false)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = app.clone().oneshot(consent(false, true)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert!(decision(body)
+            .query_pairs()
+            .any(|(key, value)| key == "error" && value == "access_denied"));
+
+        let response = app.clone().oneshot(consent(true, true)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert!(decision(body).query_pairs().any(|(key, _)| key == "code"));
+
+        // Approval is remembered, also for narrower scopes.
+        let mut narrower = params.clone();
+        narrower["scope"] = "openid".into();
+        let response = app.clone().oneshot(authorize(&narrower)).await.unwrap();
+        let location = response.headers()[http::header::LOCATION].to_str().unwrap();
+        assert!(location.starts_with("https://app.gravitalia.com/callback?code="));
+
+        // Wider scopes need a new approval.
+        let mut wider = params.clone();
+        wider["scope"] = "openid email profile".into();
+        let response = app.oneshot(authorize(&wider)).await.unwrap();
+        let location = response.headers()[http::header::LOCATION].to_str().unwrap();
+        assert!(location.starts_with("https://account.gravitalia.com/consent?"));
+    }
+}
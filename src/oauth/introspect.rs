@@ -0,0 +1,247 @@
+//! Token introspection endpoint (RFC 7662 <https://datatracker.ietf.org/doc/html/rfc7662>).
+//!
+//! Path: /oauth/introspect
+
+use axum::extract::State;
+use axum::http::{header, HeaderMap};
+use axum::response::IntoResponse;
+use axum::{Form, Json};
+use serde::{Deserialize, Serialize};
+
+use crate::{client::Client, database::Database};
+
+use super::Error;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Body {
+    token: String,
+    token_type_hint: Option<String>,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Response {
+    active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sub: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exp: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    iat: Option<i64>,
+}
+
+/// Meta-information about a token, for resource servers.
+pub async fn handler(
+    State(db): State<Database>,
+    headers: HeaderMap,
+    Form(body): Form<Body>,
+) -> Result<impl IntoResponse, Error> {
+    let client = super::authenticate_client(
+        &db,
+        &headers,
+        body.client_id.as_deref(),
+        body.client_secret.as_deref(),
+    )
+    .await?;
+    // Otherwise, anyone could probe tokens with a public `client_id`.
+    if client.is_public() {
+        return Err(Error::InvalidClient(
+            "Only confidential clients can introspect tokens.".into(),
+        ));
+    }
+
+    let response = if body.token_type_hint.as_deref() == Some("refresh_token") {
+        match refresh_token(&db, &body.token).await? {
+            Some(response) => Some(response),
+            None => access_token(&db, &body.token).await?,
+        }
+    } else {
+        match access_token(&db, &body.token).await? {
+            Some(response) => Some(response),
+            None => refresh_token(&db, &body.token).await?,
+        }
+    }
+    .filter(|response| audience(&client, response));
+
+    Ok((
+        [(header::CACHE_CONTROL, "no-store")],
+        Json(response.unwrap_or_default()),
+    ))
+}
+
+/// Whether the client may learn about the token: resource servers see all tokens,
+/// others only those issued to themselves.
+fn audience(client: &Client, response: &Response) -> bool {
+    client.resource_server || response.client_id.as_deref() == Some(&client.client_id)
+}
+
+/// Introspect an access token, `None` if it does not exist.
+async fn access_token(db: &Database, token: &str) -> Result<Option<Response>, Error> {
+    let row = sqlx::query!(
+        r#"SELECT t.user_vanity, t.client_id, t.scope,
+        EXTRACT(EPOCH FROM t.expire_at)::BIGINT AS "exp!", EXTRACT(EPOCH FROM t.created_at)::BIGINT AS "iat!",
+        t.expire_at > NOW() AND u.suspended_at IS NULL AND u.deleted_at IS NULL AS "active!"
+        FROM tokens t JOIN users u ON u.vanity = t.user_vanity
+        WHERE t.token = $1"#,
+        token,
+    )
+    .fetch_optional(&db.postgres)
+    .await?;
+
+    Ok(row.map(|row| {
+        if !row.active {
+            return Response::default();
+        }
+
+        Response {
+            active: true,
+            sub: Some(row.user_vanity),
+            scope: row.scope,
+            client_id: row.client_id,
+            token_type: Some("Bearer".into()),
+            exp: Some(row.exp),
+            iat: Some(row.iat),
+        }
+    }))
+}
+
+/// Introspect a refresh token, `None` if it does not exist.
+async fn refresh_token(db: &Database, token: &str) -> Result<Option<Response>, Error> {
+    let row = sqlx::query!(
+        r#"SELECT r.user_vanity, r.client_id, r.scope,
+        EXTRACT(EPOCH FROM r.expire_at)::BIGINT AS "exp!", EXTRACT(EPOCH FROM r.created_at)::BIGINT AS "iat!",
+        r.rotated_at IS NULL AND r.expire_at > NOW() AND u.suspended_at IS NULL AND u.deleted_at IS NULL AS "active!"
+        FROM refresh_tokens r JOIN users u ON u.vanity = r.user_vanity
+        WHERE r.token = $1"#,
+        token,
+    )
+    .fetch_optional(&db.postgres)
+    .await?;
+
+    Ok(row.map(|row| {
+        if !row.active {
+            return Response::default();
+        }
+
+        Response {
+            active: true,
+            sub: Some(row.user_vanity),
+            scope: Some(row.scope),
+            client_id: Some(row.client_id),
+            token_type: Some("refresh_token".into()),
+            exp: Some(row.exp),
+            iat: Some(row.iat),
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+    use axum::{
+        body::Body as RequestBody,
+        http::{self, Request, StatusCode},
+    };
+    use http_body_util::BodyExt;
+    use sqlx::{Pool, Postgres};
+    use tower::ServiceExt;
+
+    #[sqlx::test]
+    async fn test_introspect_handler(pool: Pool<Postgres>) {
+        sqlx::query!(
+            r#"INSERT INTO "users" (vanity, username, email, password) values ($1, $2, $3, $4)"#,
+            "user",
+            "User",
+            "test@gravitalia.com",
+            "",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query!(
+            r#"INSERT INTO "clients" (client_id, client_secret, registration_access_token, redirect_uris)
+            values ($1, $2, $3, $4)"#,
+            "api",
+            crypto::hash_password(b"secret").unwrap(),
+            "registration",
+            &["https://api.gravitalia.com/callback".to_string()],
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let user = user::User::default()
+            .with_vanity("user".into())
+            .get(&pool)
+            .await
+            .unwrap();
+        let token = user
+            .generate_client_token(&pool, "api", "openid", "family")
+            .await
+            .unwrap();
+        let first_party = user.generate_token(&pool).await.unwrap();
+
+        let state = AppState {
+            db: database::Database {
+                postgres: pool.clone(),
+            },
+            config: status::Configuration::default(),
+            keys: jwt::KeyManager::default(),
+            mailer: std::sync::Arc::new(mail::Stdout),
+            captcha: None,
+        };
+        let app = app(state);
+
+        let request = |token: &str| {
+            Request::builder()
+                .method(http::Method::POST)
+                .uri("/oauth/introspect")
+                .header(
+                    http::header::CONTENT_TYPE,
+                    "application/x-www-form-urlencoded",
+                )
+                // base64("api:secret")
+                .header(http::header::AUTHORIZATION, "Basic YXBpOnNlY3JldA==")
+                .body(RequestBody::from(format!("token={}", token)))
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(request(&token)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Response = serde_json::from_slice(&body).unwrap();
+        assert!(body.active);
+        assert_eq!(body.sub.as_deref(), Some("user"));
+        assert_eq!(body.client_id.as_deref(), Some("api"));
+        assert_eq!(body.scope.as_deref(), Some("openid"));
+
+        let response = app.clone().oneshot(request("unknown")).await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Response = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body, Response::default());
+
+        // Tokens of others are only disclosed to resource servers.
+        let response = app.clone().oneshot(request(&first_party)).await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Response = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body, Response::default());
+
+        sqlx::query!(r#"UPDATE "clients" SET resource_server = TRUE"#)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let response = app.oneshot(request(&first_party)).await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Response = serde_json::from_slice(&body).unwrap();
+        assert!(body.active);
+        assert_eq!(body.sub.as_deref(), Some("user"));
+    }
+}
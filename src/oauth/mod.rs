@@ -0,0 +1,252 @@
+//! Based on OAuth 2.0 (RFC 6749 <https://datatracker.ietf.org/doc/html/rfc6749>)
+//! with mandatory PKCE (RFC 7636 <https://datatracker.ietf.org/doc/html/rfc7636>).
+
+pub mod authorize;
+pub mod introspect;
+pub mod register;
+pub mod revoke;
+pub mod token;
+pub mod userinfo;
+
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::{client::Client, database::Database, ratelimit::RateLimitLayer, AppState};
+
+/// Scopes clients may request.
+pub const SCOPES: &[&str] = &["openid", "profile", "email"];
+
+pub fn oauth(state: AppState, limiter: RateLimitLayer) -> Router {
+    Router::new()
+        .route("/authorize", get(authorize::handler).post(authorize::consent))
+        .route("/token", post(token::handler).layer(limiter.clone()))
+        .route("/introspect", post(introspect::handler).layer(limiter.clone()))
+        .route("/revoke", post(revoke::handler).layer(limiter.clone()))
+        .route("/userinfo", get(userinfo::handler).post(userinfo::handler))
+        .route("/register", post(register::register).layer(limiter))
+        .route(
+            "/register/{client_id}",
+            get(register::read)
+                .put(register::update)
+                .delete(register::delete),
+        )
+        .with_state(state)
+}
+
+/// Error codes defined by RFC 6749, RFC 6750 and RFC 7591.
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("invalid_request")]
+    InvalidRequest(String),
+
+    #[error("invalid_client")]
+    InvalidClient(String),
+
+    #[error("invalid_grant")]
+    InvalidGrant(String),
+
+    #[error("invalid_scope")]
+    InvalidScope(String),
+
+    #[error("unauthorized_client")]
+    UnauthorizedClient(String),
+
+    #[error("unsupported_grant_type")]
+    UnsupportedGrantType(String),
+
+    #[error("unsupported_response_type")]
+    UnsupportedResponseType(String),
+
+    #[error("access_denied")]
+    AccessDenied(String),
+
+    #[error("invalid_token")]
+    InvalidToken(String),
+
+    #[error("invalid_redirect_uri")]
+    InvalidRedirectUri(String),
+
+    #[error("invalid_client_metadata")]
+    InvalidClientMetadata(String),
+
+    #[error("server_error")]
+    Sql(#[from] sqlx::Error),
+
+    #[error("server_error")]
+    Jwt(#[from] crate::jwt::Error),
+
+    #[error("server_error")]
+    Internal(String),
+}
+
+impl Error {
+    /// Human-readable description of the error.
+    pub fn description(&self) -> String {
+        match self {
+            Error::InvalidRequest(desc)
+            | Error::InvalidClient(desc)
+            | Error::InvalidGrant(desc)
+            | Error::InvalidScope(desc)
+            | Error::UnauthorizedClient(desc)
+            | Error::UnsupportedGrantType(desc)
+            | Error::UnsupportedResponseType(desc)
+            | Error::AccessDenied(desc)
+            | Error::InvalidToken(desc)
+            | Error::InvalidRedirectUri(desc)
+            | Error::InvalidClientMetadata(desc) => desc.clone(),
+            Error::Sql(_) | Error::Jwt(_) | Error::Internal(_) => {
+                "The authorization server encountered an unexpected condition.".into()
+            }
+        }
+    }
+}
+
+/// Error response body (RFC 6749 section 5.2).
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    error: String,
+    error_description: String,
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        let status = match self {
+            Error::InvalidClient(_) | Error::AccessDenied(_) | Error::InvalidToken(_) => {
+                StatusCode::UNAUTHORIZED
+            }
+            Error::Sql(_) | Error::Jwt(_) | Error::Internal(_) => {
+                tracing::error!(error = ?self, "oauth request failed");
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            _ => StatusCode::BAD_REQUEST,
+        };
+
+        let mut response = (
+            status,
+            [(header::CACHE_CONTROL, "no-store")],
+            Json(ErrorResponse {
+                error: self.to_string(),
+                error_description: self.description(),
+            }),
+        )
+            .into_response();
+
+        // Protected resources must challenge the client (RFC 6750 section 3).
+        if let Error::InvalidToken(_) = self {
+            response.headers_mut().insert(
+                header::WWW_AUTHENTICATE,
+                HeaderValue::from_static(r#"Bearer error="invalid_token""#),
+            );
+        }
+
+        response
+    }
+}
+
+/// Cookie holding the first-party token, as browsers reach `/oauth/authorize`
+/// by navigation, without an `Authorization` header.
+pub const SESSION_COOKIE: &str = "__Host-session";
+/// First-party tokens expire after a month.
+const SESSION_MAX_AGE: u64 = 30 * 24 * 60 * 60;
+
+/// `Set-Cookie` value opening a browser session with a first-party token.
+pub fn session_cookie(token: &str) -> String {
+    format!(
+        "{}={}; Path=/; Max-Age={}; HttpOnly; Secure; SameSite=Lax",
+        SESSION_COOKIE, token, SESSION_MAX_AGE
+    )
+}
+
+/// `Set-Cookie` value ending the browser session.
+pub fn clear_session_cookie() -> String {
+    format!(
+        "{}=; Path=/; Max-Age=0; HttpOnly; Secure; SameSite=Lax",
+        SESSION_COOKIE
+    )
+}
+
+/// Extract the token from the session cookie.
+pub fn session(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(name, _)| *name == SESSION_COOKIE)
+        .map(|(_, token)| token)
+        .filter(|token| !token.is_empty())
+}
+
+/// Extract the token from an `Authorization: Bearer` header.
+pub fn bearer(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+}
+
+/// Authenticate a client using HTTP Basic or request body credentials.
+///
+/// Public clients only have to identify themselves with their `client_id`.
+pub async fn authenticate_client(
+    db: &Database,
+    headers: &HeaderMap,
+    client_id: Option<&str>,
+    client_secret: Option<&str>,
+) -> Result<Client, Error> {
+    let basic = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Basic "))
+        .and_then(|value| STANDARD.decode(value.trim()).ok())
+        .and_then(|value| String::from_utf8(value).ok());
+
+    let (client_id, client_secret) = match &basic {
+        Some(credentials) => credentials
+            .split_once(':')
+            .map(|(id, secret)| (id, Some(secret)))
+            .ok_or_else(|| Error::InvalidClient("Malformed client credentials.".into()))?,
+        None => (
+            client_id
+                .ok_or_else(|| Error::InvalidClient("Missing client authentication.".into()))?,
+            client_secret,
+        ),
+    };
+
+    let client = match Client::get(&db.postgres, client_id).await {
+        Ok(client) => client,
+        Err(sqlx::Error::RowNotFound) => {
+            return Err(Error::InvalidClient("Unknown client.".into()))
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    if !client.is_public() && !client_secret.is_some_and(|secret| client.verify_secret(secret)) {
+        return Err(Error::InvalidClient("Client authentication failed.".into()));
+    }
+
+    Ok(client)
+}
+
+/// Check that a PKCE code verifier or challenge is 43 to 128 unreserved characters long.
+pub fn is_valid_pkce(value: &str) -> bool {
+    (43..=128).contains(&value.len())
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~'))
+}
+
+/// Compute the S256 code challenge of a PKCE code verifier.
+pub fn pkce_challenge(verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
@@ -0,0 +1,540 @@
+//! Dynamic client registration (RFC 7591 <https://datatracker.ietf.org/doc/html/rfc7591>)
+//! and management (RFC 7592 <https://datatracker.ietf.org/doc/html/rfc7592>).
+//!
+//! Path: /oauth/register[/CLIENT_ID]
+//!
+//! Registration is open to first-party users, and to holders of the
+//! `INITIAL_ACCESS_TOKEN` set by the operator.
+
+use axum::extract::{Path, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::IntoResponse;
+use axum::Json;
+use rand::distributions::{Alphanumeric, DistString};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+use url::Url;
+
+use crate::jwt::Algorithm;
+use crate::status::Configuration;
+use crate::{client::Client, database::Database, user::User};
+
+use super::Error;
+
+const CLIENT_ID_LENGTH: usize = 24;
+const CLIENT_SECRET_LENGTH: usize = 48;
+const REGISTRATION_TOKEN_LENGTH: usize = 64;
+
+const GRANT_TYPES: [&str; 2] = ["authorization_code", "refresh_token"];
+const AUTH_METHODS: [&str; 3] = ["none", "client_secret_basic", "client_secret_post"];
+
+/// Client metadata (RFC 7591 section 2).
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Metadata {
+    #[serde(default)]
+    redirect_uris: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_name: Option<String>,
+    #[serde(default = "default_grant_types")]
+    grant_types: Vec<String>,
+    #[serde(default = "default_response_types")]
+    response_types: Vec<String>,
+    #[serde(default = "default_auth_method")]
+    token_endpoint_auth_method: String,
+    #[serde(default = "default_signing_alg")]
+    id_token_signed_response_alg: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    logo_uri: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    policy_uri: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tos_uri: Option<String>,
+}
+
+fn default_grant_types() -> Vec<String> {
+    vec!["authorization_code".into()]
+}
+
+fn default_response_types() -> Vec<String> {
+    vec!["code".into()]
+}
+
+fn default_auth_method() -> String {
+    "client_secret_basic".into()
+}
+
+fn default_signing_alg() -> String {
+    Algorithm::RS256.as_str().into()
+}
+
+/// Whether a redirect URI may receive authorization codes.
+///
+/// Native apps use loopback HTTP or a reverse domain name scheme
+/// (RFC 8252 section 7 <https://datatracker.ietf.org/doc/html/rfc8252#section-7>).
+fn allowed_redirect_uri(url: &Url) -> bool {
+    match url.scheme() {
+        "https" => true,
+        "http" => match url.host() {
+            Some(url::Host::Domain(domain)) => domain == "localhost",
+            Some(url::Host::Ipv4(ip)) => ip.is_loopback(),
+            Some(url::Host::Ipv6(ip)) => ip.is_loopback(),
+            None => false,
+        },
+        scheme => scheme.contains('.'),
+    }
+}
+
+impl Metadata {
+    /// Reject metadata the server cannot honor.
+    fn validate(&self) -> Result<(), Error> {
+        if self.redirect_uris.is_empty() {
+            return Err(Error::InvalidRedirectUri(
+                "At least one `redirect_uris` is required.".into(),
+            ));
+        }
+        for uri in &self.redirect_uris {
+            let url = Url::parse(uri)
+                .map_err(|_| Error::InvalidRedirectUri(format!("`{}` is not an URL.", uri)))?;
+            if url.fragment().is_some() {
+                return Err(Error::InvalidRedirectUri(format!(
+                    "`{}` must not contain a fragment.",
+                    uri
+                )));
+            }
+            if !allowed_redirect_uri(&url) {
+                return Err(Error::InvalidRedirectUri(format!(
+                    "`{}` must use HTTPS, loopback HTTP or a private-use scheme.",
+                    uri
+                )));
+            }
+        }
+
+        for uri in [&self.logo_uri, &self.policy_uri, &self.tos_uri]
+            .into_iter()
+            .flatten()
+        {
+            Url::parse(uri)
+                .map_err(|_| Error::InvalidClientMetadata(format!("`{}` is not an URL.", uri)))?;
+        }
+
+        if self.grant_types.is_empty()
+            || !self
+                .grant_types
+                .iter()
+                .all(|grant| GRANT_TYPES.contains(&grant.as_str()))
+        {
+            return Err(Error::InvalidClientMetadata(format!(
+                "`grant_types` must be among {:?}.",
+                GRANT_TYPES
+            )));
+        }
+        if self
+            .response_types
+            .iter()
+            .any(|response| response != "code")
+        {
+            return Err(Error::InvalidClientMetadata(
+                "Only `code` response type is supported.".into(),
+            ));
+        }
+        if !AUTH_METHODS.contains(&self.token_endpoint_auth_method.as_str()) {
+            return Err(Error::InvalidClientMetadata(format!(
+                "`token_endpoint_auth_method` must be among {:?}.",
+                AUTH_METHODS
+            )));
+        }
+        if self
+            .id_token_signed_response_alg
+            .parse::<Algorithm>()
+            .is_err()
+        {
+            return Err(Error::InvalidClientMetadata(
+                "Unsupported `id_token_signed_response_alg`.".into(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl From<Client> for Metadata {
+    fn from(client: Client) -> Self {
+        Self {
+            redirect_uris: client.redirect_uris,
+            client_name: client.client_name,
+            grant_types: client.grant_types,
+            response_types: default_response_types(),
+            token_endpoint_auth_method: client.token_endpoint_auth_method,
+            id_token_signed_response_alg: client.id_token_signed_response_alg,
+            logo_uri: client.logo_uri,
+            policy_uri: client.policy_uri,
+            tos_uri: client.tos_uri,
+        }
+    }
+}
+
+/// Client information response (RFC 7591 section 3.2.1).
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Response {
+    client_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_secret: Option<String>,
+    client_id_issued_at: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_secret_expires_at: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    registration_access_token: Option<String>,
+    registration_client_uri: String,
+    #[serde(flatten)]
+    metadata: Metadata,
+}
+
+impl Response {
+    fn new(config: &Configuration, client: Client) -> Self {
+        let registration_client_uri = Url::parse(&config.url)
+            .and_then(|url| url.join(&format!("oauth/register/{}", client.client_id)))
+            .map(|url| url.to_string())
+            .unwrap_or_default();
+
+        Self {
+            client_id: client.client_id.clone(),
+            client_secret: None,
+            client_id_issued_at: client.issued_at,
+            client_secret_expires_at: None,
+            registration_access_token: None,
+            registration_client_uri,
+            metadata: client.into(),
+        }
+    }
+}
+
+/// Check the initial access token of a registration (RFC 7591 section 3).
+async fn authorize(db: &Database, headers: &HeaderMap) -> Result<(), Error> {
+    let token = super::bearer(headers)
+        .ok_or_else(|| Error::InvalidToken("Missing initial access token.".into()))?;
+
+    if std::env::var("INITIAL_ACCESS_TOKEN")
+        .is_ok_and(|initial| bool::from(initial.as_bytes().ct_eq(token.as_bytes())))
+    {
+        return Ok(());
+    }
+
+    match User::from_token(&db.postgres, token).await {
+        Ok(_) => Ok(()),
+        Err(sqlx::Error::RowNotFound) => Err(Error::InvalidToken(
+            "Initial access token is invalid.".into(),
+        )),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Register a new client.
+pub async fn register(
+    State(db): State<Database>,
+    State(config): State<Configuration>,
+    headers: HeaderMap,
+    Json(metadata): Json<Metadata>,
+) -> Result<impl IntoResponse, Error> {
+    authorize(&db, &headers).await?;
+    metadata.validate()?;
+
+    let client_id = Alphanumeric.sample_string(&mut OsRng, CLIENT_ID_LENGTH);
+    let registration_access_token =
+        Alphanumeric.sample_string(&mut OsRng, REGISTRATION_TOKEN_LENGTH);
+    let client_secret = (metadata.token_endpoint_auth_method != "none")
+        .then(|| Alphanumeric.sample_string(&mut OsRng, CLIENT_SECRET_LENGTH));
+    let hash = client_secret
+        .as_deref()
+        .map(|secret| crate::crypto::hash_password(secret.as_bytes()))
+        .transpose()
+        .map_err(|err| Error::Internal(err.to_string()))?;
+
+    sqlx::query!(
+        r#"INSERT INTO "clients" (client_id, client_secret, registration_access_token, client_name, redirect_uris,
+        grant_types, token_endpoint_auth_method, id_token_signed_response_alg, logo_uri, policy_uri, tos_uri)
+        values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)"#,
+        client_id,
+        hash,
+        crate::crypto::hash_token(&registration_access_token),
+        metadata.client_name,
+        &metadata.redirect_uris,
+        &metadata.grant_types,
+        metadata.token_endpoint_auth_method,
+        metadata.id_token_signed_response_alg,
+        metadata.logo_uri,
+        metadata.policy_uri,
+        metadata.tos_uri,
+    )
+    .execute(&db.postgres)
+    .await?;
+
+    let client = Client::get(&db.postgres, &client_id).await?;
+    let mut response = Response::new(&config, client);
+    // Secret and token are only known at registration time, they are stored hashed.
+    response.client_secret_expires_at = client_secret.as_ref().map(|_| 0);
+    response.client_secret = client_secret;
+    response.registration_access_token = Some(registration_access_token);
+
+    Ok((
+        StatusCode::CREATED,
+        [(header::CACHE_CONTROL, "no-store")],
+        Json(response),
+    ))
+}
+
+/// Get the client authenticated by its registration access token.
+async fn authenticate(
+    db: &Database,
+    headers: &HeaderMap,
+    client_id: &str,
+) -> Result<Client, Error> {
+    let token = super::bearer(headers)
+        .ok_or_else(|| Error::InvalidToken("Missing registration access token.".into()))?;
+
+    let hash = crate::crypto::hash_token(token);
+
+    // Unknown clients and invalid tokens are indistinguishable.
+    match Client::get(&db.postgres, client_id).await {
+        Ok(client)
+            if bool::from(
+                client
+                    .registration_access_token
+                    .as_bytes()
+                    .ct_eq(hash.as_bytes()),
+            ) =>
+        {
+            Ok(client)
+        }
+        Ok(_) | Err(sqlx::Error::RowNotFound) => Err(Error::InvalidToken(
+            "Registration access token is invalid.".into(),
+        )),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Read the current client configuration.
+pub async fn read(
+    State(db): State<Database>,
+    State(config): State<Configuration>,
+    Path(client_id): Path<String>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, Error> {
+    let client = authenticate(&db, &headers, &client_id).await?;
+
+    Ok((
+        [(header::CACHE_CONTROL, "no-store")],
+        Json(Response::new(&config, client)),
+    ))
+}
+
+/// Replace the client metadata.
+pub async fn update(
+    State(db): State<Database>,
+    State(config): State<Configuration>,
+    Path(client_id): Path<String>,
+    headers: HeaderMap,
+    Json(metadata): Json<Metadata>,
+) -> Result<impl IntoResponse, Error> {
+    let client = authenticate(&db, &headers, &client_id).await?;
+    metadata.validate()?;
+
+    // Switching between public and confidential would require issuing or dropping a secret.
+    if (metadata.token_endpoint_auth_method == "none") != client.is_public() {
+        return Err(Error::InvalidClientMetadata(
+            "`token_endpoint_auth_method` cannot switch between public and confidential.".into(),
+        ));
+    }
+
+    sqlx::query!(
+        r#"UPDATE "clients" SET client_name = $2, redirect_uris = $3, grant_types = $4,
+        token_endpoint_auth_method = $5, id_token_signed_response_alg = $6, logo_uri = $7,
+        policy_uri = $8, tos_uri = $9 WHERE client_id = $1"#,
+        client.client_id,
+        metadata.client_name,
+        &metadata.redirect_uris,
+        &metadata.grant_types,
+        metadata.token_endpoint_auth_method,
+        metadata.id_token_signed_response_alg,
+        metadata.logo_uri,
+        metadata.policy_uri,
+        metadata.tos_uri,
+    )
+    .execute(&db.postgres)
+    .await?;
+
+    let client = Client::get(&db.postgres, &client.client_id).await?;
+
+    Ok((
+        [(header::CACHE_CONTROL, "no-store")],
+        Json(Response::new(&config, client)),
+    ))
+}
+
+/// Deregister the client, revoking every token issued to it.
+pub async fn delete(
+    State(db): State<Database>,
+    Path(client_id): Path<String>,
+    headers: HeaderMap,
+) -> Result<StatusCode, Error> {
+    let client = authenticate(&db, &headers, &client_id).await?;
+
+    sqlx::query!(
+        r#"DELETE FROM "clients" WHERE client_id = $1"#,
+        client.client_id
+    )
+    .execute(&db.postgres)
+    .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+    use axum::{
+        body::Body as RequestBody,
+        http::{self, Request, StatusCode},
+    };
+    use http_body_util::BodyExt;
+    use sqlx::{Pool, Postgres};
+    use tower::ServiceExt;
+
+    #[test]
+    fn test_allowed_redirect_uri() {
+        for (uri, allowed) in [
+            ("https://app.gravitalia.com/callback", true),
+            ("http://127.0.0.1:8080/callback", true),
+            ("http://[::1]/callback", true),
+            ("http://localhost/callback", true),
+            ("com.gravitalia.app:/callback", true),
+            ("http://app.gravitalia.com/callback", false),
+            ("javascript:alert(1)", false),
+            ("data:text/html,callback", false),
+            ("file:///etc/passwd", false),
+        ] {
+            assert_eq!(
+                allowed_redirect_uri(&Url::parse(uri).unwrap()),
+                allowed,
+                "{}",
+                uri
+            );
+        }
+    }
+
+    #[sqlx::test]
+    async fn test_register_handler(pool: Pool<Postgres>) {
+        sqlx::query!(
+            r#"INSERT INTO "users" (vanity, username, email, password) values ($1, $2, $3, $4)"#,
+            "user",
+            "User",
+            "test@gravitalia.com",
+            "",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let token = user::User::default()
+            .with_vanity("user".into())
+            .get(&pool)
+            .await
+            .unwrap()
+            .generate_token(&pool)
+            .await
+            .unwrap();
+
+        let mut config = status::Configuration::default();
+        config.url = "https://auth.gravitalia.com/".into();
+        let state = AppState {
+            db: database::Database { postgres: pool },
+            config,
+            keys: jwt::KeyManager::default(),
+            mailer: std::sync::Arc::new(mail::Stdout),
+            captcha: None,
+        };
+        let app = app(state);
+
+        let body = serde_json::json!({
+            "client_name": "Gravitalia",
+            "redirect_uris": ["https://app.gravitalia.com/callback"],
+            "grant_types": ["authorization_code", "refresh_token"],
+            "logo_uri": "https://app.gravitalia.com/logo.png",
+        });
+        let register = |token: Option<&str>| {
+            let request = Request::builder()
+                .method(http::Method::POST)
+                .uri("/oauth/register")
+                .header(http::header::CONTENT_TYPE, "application/json");
+            let request = match token {
+                Some(token) => {
+                    request.header(http::header::AUTHORIZATION, format!("Bearer {}", token))
+                }
+                None => request,
+            };
+            request.body(RequestBody::from(body.to_string())).unwrap()
+        };
+
+        // Anonymous registration is closed.
+        let response = app.clone().oneshot(register(None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = app
+            .clone()
+            .oneshot(register(Some("unknown")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = app.clone().oneshot(register(Some(&token))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let client: Response = serde_json::from_slice(&body).unwrap();
+        assert!(client.client_secret.is_some());
+        assert_eq!(
+            client.registration_client_uri,
+            format!(
+                "https://auth.gravitalia.com/oauth/register/{}",
+                client.client_id
+            )
+        );
+
+        let request = |method: http::Method| {
+            Request::builder()
+                .method(method)
+                .uri(format!("/oauth/register/{}", client.client_id))
+                .header(
+                    http::header::AUTHORIZATION,
+                    format!(
+                        "Bearer {}",
+                        client.registration_access_token.as_ref().unwrap()
+                    ),
+                )
+                .body(RequestBody::empty())
+                .unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(request(http::Method::GET))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let read: Response = serde_json::from_slice(&body).unwrap();
+        assert_eq!(read.client_secret, None);
+        assert_eq!(read.registration_access_token, None);
+        assert_eq!(read.metadata, client.metadata);
+
+        let response = app
+            .clone()
+            .oneshot(request(http::Method::DELETE))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let response = app.oneshot(request(http::Method::GET)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,166 @@
+//! Token revocation endpoint (RFC 7009 <https://datatracker.ietf.org/doc/html/rfc7009>).
+//!
+//! Path: /oauth/revoke
+
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::Form;
+use serde::{Deserialize, Serialize};
+
+use crate::database::Database;
+
+use super::Error;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Body {
+    token: String,
+    token_type_hint: Option<String>,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+}
+
+/// Revoke a token issued to the authenticated client.
+///
+/// Revoking a refresh token also revokes every access token of its family.
+/// Unknown tokens, or tokens of other clients, are silently ignored.
+pub async fn handler(
+    State(db): State<Database>,
+    headers: HeaderMap,
+    Form(body): Form<Body>,
+) -> Result<StatusCode, Error> {
+    let client = super::authenticate_client(
+        &db,
+        &headers,
+        body.client_id.as_deref(),
+        body.client_secret.as_deref(),
+    )
+    .await?;
+
+    let mut tx = db.postgres.begin().await?;
+
+    let family = sqlx::query_scalar!(
+        r#"DELETE FROM "refresh_tokens" WHERE token = $1 AND client_id = $2 RETURNING family"#,
+        body.token,
+        client.client_id,
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    match family {
+        Some(family) => {
+            sqlx::query!(r#"DELETE FROM "refresh_tokens" WHERE family = $1"#, family)
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query!(r#"DELETE FROM "tokens" WHERE family = $1"#, family)
+                .execute(&mut *tx)
+                .await?;
+        }
+        None => {
+            sqlx::query!(
+                r#"DELETE FROM "tokens" WHERE token = $1 AND client_id = $2"#,
+                body.token,
+                client.client_id,
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+    }
+
+    tx.commit().await?;
+
+    Ok(StatusCode::OK)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use axum::{
+        body::Body as RequestBody,
+        http::{self, Request, StatusCode},
+    };
+    use sqlx::{Pool, Postgres};
+    use tower::ServiceExt;
+
+    #[sqlx::test]
+    async fn test_revoke_handler(pool: Pool<Postgres>) {
+        sqlx::query!(
+            r#"INSERT INTO "users" (vanity, username, email, password) values ($1, $2, $3, $4)"#,
+            "user",
+            "User",
+            "test@gravitalia.com",
+            "",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query!(
+            r#"INSERT INTO "clients" (client_id, registration_access_token, redirect_uris, token_endpoint_auth_method)
+            values ($1, $2, $3, $4)"#,
+            "app",
+            "registration",
+            &["https://app.gravitalia.com/callback".to_string()],
+            "none",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let access_token = user::User::default()
+            .with_vanity("user".into())
+            .get(&pool)
+            .await
+            .unwrap()
+            .generate_client_token(&pool, "app", "openid", "family")
+            .await
+            .unwrap();
+        sqlx::query!(
+            r#"INSERT INTO "refresh_tokens" (token, family, user_vanity, client_id) values ($1, $2, $3, $4)"#,
+            "refresh",
+            "family",
+            "user",
+            "app",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let state = AppState {
+            db: database::Database {
+                postgres: pool.clone(),
+            },
+            config: status::Configuration::default(),
+            keys: jwt::KeyManager::default(),
+            mailer: std::sync::Arc::new(mail::Stdout),
+            captcha: None,
+        };
+        let app = app(state);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/oauth/revoke")
+                    .header(
+                        http::header::CONTENT_TYPE,
+                        "application/x-www-form-urlencoded",
+                    )
+                    .body(RequestBody::from(
+                        "token=refresh&token_type_hint=refresh_token&client_id=app",
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Access tokens of the family are revoked too.
+        let remaining = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) AS "count!" FROM "tokens" WHERE token = $1"#,
+            access_token
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(remaining, 0);
+    }
+}
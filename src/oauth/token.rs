@@ -0,0 +1,527 @@
+//! Token endpoint (RFC 6749 section 3.2).
+//!
+//! Path: /oauth/token
+
+use axum::extract::State;
+use axum::http::{header, HeaderMap};
+use axum::response::IntoResponse;
+use axum::{Form, Json};
+use rand::distributions::{Alphanumeric, DistString};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+
+use crate::jwt::{Algorithm, IdToken, KeyManager};
+use crate::status::Configuration;
+use crate::user::{User, CLIENT_TOKEN_LIFETIME};
+use crate::{client::Client, database::Database};
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::Error;
+
+/// ID Token lifetime, in seconds.
+const ID_TOKEN_LIFETIME: u64 = 60 * 60;
+const REFRESH_TOKEN_LENGTH: usize = 64;
+const FAMILY_LENGTH: usize = 32;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Body {
+    grant_type: String,
+    code: Option<String>,
+    redirect_uri: Option<String>,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    code_verifier: Option<String>,
+    refresh_token: Option<String>,
+    scope: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Response {
+    access_token: String,
+    token_type: String,
+    expires_in: i64,
+    refresh_token: String,
+    scope: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id_token: Option<String>,
+}
+
+pub async fn handler(
+    State(db): State<Database>,
+    State(config): State<Configuration>,
+    State(keys): State<KeyManager>,
+    headers: HeaderMap,
+    Form(body): Form<Body>,
+) -> Result<impl IntoResponse, Error> {
+    if !matches!(
+        body.grant_type.as_str(),
+        "authorization_code" | "refresh_token"
+    ) {
+        return Err(Error::UnsupportedGrantType(
+            "Only `authorization_code` and `refresh_token` grant types are supported.".into(),
+        ));
+    }
+
+    let client = super::authenticate_client(
+        &db,
+        &headers,
+        body.client_id.as_deref(),
+        body.client_secret.as_deref(),
+    )
+    .await?;
+    if !client.allows_grant(&body.grant_type) {
+        return Err(Error::UnauthorizedClient(format!(
+            "Client did not register the `{}` grant type.",
+            body.grant_type
+        )));
+    }
+
+    let response = if body.grant_type == "authorization_code" {
+        authorization_code(&db, &config, &keys, client, body).await?
+    } else {
+        refresh_token(&db, client, body).await?
+    };
+
+    Ok(([(header::CACHE_CONTROL, "no-store")], Json(response)))
+}
+
+/// Exchange an authorization code, starting a new refresh token family.
+async fn authorization_code(
+    db: &Database,
+    config: &Configuration,
+    keys: &KeyManager,
+    client: Client,
+    body: Body,
+) -> Result<Response, Error> {
+    let (Some(code), Some(redirect_uri), Some(code_verifier)) =
+        (body.code, body.redirect_uri, body.code_verifier)
+    else {
+        return Err(Error::InvalidRequest(
+            "Missing `code`, `redirect_uri` or `code_verifier`.".into(),
+        ));
+    };
+
+    // Authorization codes are single-use, whatever the outcome.
+    let grant = sqlx::query!(
+        r#"DELETE FROM "authorization_codes" WHERE code = $1
+        RETURNING user_vanity, client_id, redirect_uri, scope, code_challenge, nonce, expire_at > NOW() AS "active!""#,
+        code,
+    )
+    .fetch_optional(&db.postgres)
+    .await?
+    .filter(|grant| grant.active)
+    .ok_or_else(|| Error::InvalidGrant("Authorization code is invalid or expired.".into()))?;
+
+    if grant.client_id != client.client_id || grant.redirect_uri != redirect_uri {
+        return Err(Error::InvalidGrant(
+            "Authorization code was issued to another client.".into(),
+        ));
+    }
+    if !super::is_valid_pkce(&code_verifier)
+        || super::pkce_challenge(&code_verifier) != grant.code_challenge
+    {
+        return Err(Error::InvalidGrant("PKCE verification failed.".into()));
+    }
+
+    let user = sqlx::query_as!(
+        User,
+        r#"SELECT vanity, username, email, avatar, flags, password FROM "users"
+        WHERE vanity = $1 AND suspended_at IS NULL AND deleted_at IS NULL"#,
+        grant.user_vanity,
+    )
+    .fetch_optional(&db.postgres)
+    .await?
+    .ok_or_else(|| {
+        Error::InvalidGrant("Authorization code belongs to a suspended or deleted user.".into())
+    })?;
+    let family = Alphanumeric.sample_string(&mut OsRng, FAMILY_LENGTH);
+
+    let mut tx = db.postgres.begin().await?;
+    let mut response = issue(&mut tx, &user, &client.client_id, grant.scope, &family).await?;
+    tx.commit().await?;
+
+    if response.scope.split(' ').any(|scope| scope == "openid") {
+        let iat = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let claims = IdToken {
+            iss: config.url.clone(),
+            sub: user.vanity,
+            aud: client.client_id,
+            exp: iat + ID_TOKEN_LIFETIME,
+            iat,
+            nonce: grant.nonce,
+        };
+
+        let algorithm = client
+            .id_token_signed_response_alg
+            .parse()
+            .unwrap_or(Algorithm::RS256);
+        response.id_token = Some(keys.sign(algorithm, &claims)?);
+    }
+
+    Ok(response)
+}
+
+/// Rotate a refresh token, revoking its whole family if it was already used.
+async fn refresh_token(db: &Database, client: Client, body: Body) -> Result<Response, Error> {
+    let Some(refresh_token) = body.refresh_token else {
+        return Err(Error::InvalidRequest("Missing `refresh_token`.".into()));
+    };
+
+    let mut tx = db.postgres.begin().await?;
+
+    // Lock the row so concurrent refreshes are serialized.
+    let grant = sqlx::query!(
+        r#"SELECT r.family, r.user_vanity, r.client_id, r.scope, r.rotated_at IS NOT NULL AS "rotated!",
+        r.expire_at > NOW() AS "active!", u.suspended_at IS NULL AND u.deleted_at IS NULL AS "enabled!"
+        FROM "refresh_tokens" r JOIN "users" u ON u.vanity = r.user_vanity
+        WHERE r.token = $1 FOR UPDATE OF r"#,
+        refresh_token,
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| Error::InvalidGrant("Refresh token is invalid.".into()))?;
+
+    if grant.rotated {
+        // The token leaked: either the attacker or the legitimate client
+        // already used it, so every token derived from it must go.
+        sqlx::query!(r#"DELETE FROM "tokens" WHERE family = $1"#, grant.family)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!(
+            r#"DELETE FROM "refresh_tokens" WHERE family = $1"#,
+            grant.family
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        tracing::warn!(family = grant.family, "refresh token reuse detected");
+        return Err(Error::InvalidGrant(
+            "Refresh token was already used.".into(),
+        ));
+    }
+    if !grant.active || grant.client_id != client.client_id {
+        return Err(Error::InvalidGrant(
+            "Refresh token is expired or was issued to another client.".into(),
+        ));
+    }
+    if !grant.enabled {
+        return Err(Error::InvalidGrant(
+            "Refresh token belongs to a suspended or deleted user.".into(),
+        ));
+    }
+
+    // Clients may only narrow down the original scope.
+    let scope = match body.scope {
+        Some(scope) => {
+            if !scope
+                .split(' ')
+                .all(|requested| grant.scope.split(' ').any(|granted| granted == requested))
+            {
+                return Err(Error::InvalidScope(
+                    "Requested scope exceeds the original grant.".into(),
+                ));
+            }
+            scope
+        }
+        None => grant.scope,
+    };
+
+    sqlx::query!(
+        r#"UPDATE "refresh_tokens" SET rotated_at = NOW() WHERE token = $1"#,
+        refresh_token,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let user = User::default().with_vanity(grant.user_vanity);
+    let response = issue(&mut tx, &user, &client.client_id, scope, &grant.family).await?;
+    tx.commit().await?;
+
+    Ok(response)
+}
+
+/// Issue a short-lived access token paired with a refresh token of `family`.
+async fn issue(
+    conn: &mut PgConnection,
+    user: &User,
+    client_id: &str,
+    scope: String,
+    family: &str,
+) -> Result<Response, Error> {
+    let access_token = user
+        .generate_client_token(&mut *conn, client_id, &scope, family)
+        .await?;
+    let refresh_token = Alphanumeric.sample_string(&mut OsRng, REFRESH_TOKEN_LENGTH);
+
+    sqlx::query!(
+        r#"INSERT INTO "refresh_tokens" (token, family, user_vanity, client_id, scope) values ($1, $2, $3, $4, $5)"#,
+        refresh_token,
+        family,
+        user.vanity,
+        client_id,
+        scope,
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(Response {
+        access_token,
+        token_type: "Bearer".into(),
+        expires_in: CLIENT_TOKEN_LIFETIME,
+        refresh_token,
+        scope,
+        id_token: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+    use axum::{
+        body::Body as RequestBody,
+        http::{self, Request, StatusCode},
+        Router,
+    };
+    use http_body_util::BodyExt;
+    use sqlx::{Pool, Postgres};
+    use tower::ServiceExt;
+
+    const VERIFIER: &str = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+
+    async fn setup(pool: Pool<Postgres>) -> (Router, String) {
+        sqlx::query!(
+            r#"INSERT INTO "users" (vanity, username, email, password) values ($1, $2, $3, $4)"#,
+            "user",
+            "User",
+            "test@gravitalia.com",
+            "",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let token = User::default()
+            .with_vanity("user".into())
+            .get(&pool)
+            .await
+            .unwrap()
+            .generate_token(&pool)
+            .await
+            .unwrap();
+
+        sqlx::query!(
+            r#"INSERT INTO "clients" (client_id, registration_access_token, redirect_uris, grant_types, token_endpoint_auth_method)
+            values ($1, $2, $3, $4, $5)"#,
+            "app",
+            "registration",
+            &["https://app.gravitalia.com/callback".to_string()],
+            &["authorization_code".to_string(), "refresh_token".to_string()],
+            "none",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        // The user already approved the client.
+        sqlx::query!(
+            r#"INSERT INTO "consents" (user_vanity, client_id, scope) values ($1, $2, $3)"#,
+            "user",
+            "app",
+            &["openid".to_string()],
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let state = AppState {
+            keys: jwt::KeyManager::new(&pool).await.unwrap(),
+            mailer: std::sync::Arc::new(mail::Stdout),
+            captcha: None,
+            db: database::Database { postgres: pool },
+            config: status::Configuration::default(),
+        };
+
+        (app(state), token)
+    }
+
+    /// Go through the authorization endpoint and return the issued code.
+    async fn authorize(app: &Router, token: &str) -> String {
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::GET)
+                    .uri(format!(
+                        "/oauth/authorize?response_type=code&client_id=app&redirect_uri=https://app.gravitalia.com/callback&scope=openid&nonce=n-0S6_WzA2Mj&code_challenge={}&code_challenge_method=S256",
+                        oauth::pkce_challenge(VERIFIER)
+                    ))
+                    .header(http::header::AUTHORIZATION, format!("Bearer {}", token))
+                    .body(RequestBody::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+
+        let location = response.headers()[http::header::LOCATION].to_str().unwrap();
+        url::Url::parse(location)
+            .unwrap()
+            .query_pairs()
+            .find(|(key, _)| key == "code")
+            .map(|(_, value)| value.to_string())
+            .unwrap()
+    }
+
+    fn form(body: String) -> Request<RequestBody> {
+        Request::builder()
+            .method(http::Method::POST)
+            .uri("/oauth/token")
+            .header(
+                http::header::CONTENT_TYPE,
+                "application/x-www-form-urlencoded",
+            )
+            .body(RequestBody::from(body))
+            .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn test_token_handler(pool: Pool<Postgres>) {
+        let (app, token) = setup(pool).await;
+        let code = authorize(&app, &token).await;
+
+        let body = format!(
+            "grant_type=authorization_code&code={}&redirect_uri=https://app.gravitalia.com/callback&client_id=app&code_verifier={}",
+            code, VERIFIER
+        );
+
+        let response = app.clone().oneshot(form(body.clone())).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body_bytes = response.into_body().collect().await.unwrap().to_bytes();
+        let response: Response = serde_json::from_slice(&body_bytes).unwrap();
+        assert_eq!(response.token_type, "Bearer");
+        assert_eq!(response.scope, "openid");
+        assert_eq!(response.expires_in, CLIENT_TOKEN_LIFETIME);
+
+        let id_token = response.id_token.unwrap();
+        let claims = id_token.split('.').nth(1).unwrap();
+        let claims: jwt::IdToken = serde_json::from_slice(
+            &base64::Engine::decode(&base64::engine::general_purpose::URL_SAFE_NO_PAD, claims)
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(claims.sub, "user");
+        assert_eq!(claims.aud, "app");
+        assert_eq!(claims.nonce.as_deref(), Some("n-0S6_WzA2Mj"));
+
+        // Codes cannot be replayed.
+        let response = app.oneshot(form(body)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[sqlx::test]
+    async fn test_refresh_token_rotation(pool: Pool<Postgres>) {
+        let (app, token) = setup(pool).await;
+        let code = authorize(&app, &token).await;
+
+        let response = app
+            .clone()
+            .oneshot(form(format!(
+                "grant_type=authorization_code&code={}&redirect_uri=https://app.gravitalia.com/callback&client_id=app&code_verifier={}",
+                code, VERIFIER
+            )))
+            .await
+            .unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let first: Response = serde_json::from_slice(&body).unwrap();
+
+        let refresh = |refresh_token: &str| {
+            form(format!(
+                "grant_type=refresh_token&refresh_token={}&client_id=app",
+                refresh_token
+            ))
+        };
+
+        let response = app
+            .clone()
+            .oneshot(refresh(&first.refresh_token))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let second: Response = serde_json::from_slice(&body).unwrap();
+        assert_ne!(first.refresh_token, second.refresh_token);
+        assert_eq!(second.id_token, None);
+
+        // Reusing a rotated token revokes the whole family.
+        let response = app
+            .clone()
+            .oneshot(refresh(&first.refresh_token))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = app.oneshot(refresh(&second.refresh_token)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[sqlx::test]
+    async fn test_refresh_token_disabled_user(pool: Pool<Postgres>) {
+        let (app, token) = setup(pool.clone()).await;
+        let code = authorize(&app, &token).await;
+
+        let response = app
+            .clone()
+            .oneshot(form(format!(
+                "grant_type=authorization_code&code={}&redirect_uri=https://app.gravitalia.com/callback&client_id=app&code_verifier={}",
+                code, VERIFIER
+            )))
+            .await
+            .unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let response: Response = serde_json::from_slice(&body).unwrap();
+
+        sqlx::query!(r#"UPDATE "users" SET suspended_at = CURRENT_DATE WHERE vanity = 'user'"#)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let response = app
+            .oneshot(form(format!(
+                "grant_type=refresh_token&refresh_token={}&client_id=app",
+                response.refresh_token
+            )))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[sqlx::test]
+    async fn test_authorization_code_disabled_user(pool: Pool<Postgres>) {
+        let (app, token) = setup(pool.clone()).await;
+        let code = authorize(&app, &token).await;
+
+        sqlx::query!(r#"UPDATE "users" SET deleted_at = CURRENT_DATE WHERE vanity = 'user'"#)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let response = app
+            .oneshot(form(format!(
+                "grant_type=authorization_code&code={}&redirect_uri=https://app.gravitalia.com/callback&client_id=app&code_verifier={}",
+                code, VERIFIER
+            )))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "invalid_grant");
+    }
+}
@@ -0,0 +1,160 @@
+//! UserInfo endpoint (OpenID Connect Core 1.0 section 5.3).
+//!
+//! Path: /oauth/userinfo
+
+use axum::extract::State;
+use axum::http::HeaderMap;
+use axum::Json;
+use serde::{Deserialize, Serialize};
+
+use crate::database::Database;
+
+use super::Error;
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Response {
+    sub: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    preferred_username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    picture: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
+}
+
+/// Claims of the token owner, restricted to the granted scopes.
+pub async fn handler(
+    State(db): State<Database>,
+    headers: HeaderMap,
+) -> Result<Json<Response>, Error> {
+    let token = super::bearer(&headers)
+        .ok_or_else(|| Error::InvalidToken("Missing access token.".into()))?;
+
+    let row = sqlx::query!(
+        r#"SELECT t.scope, u.vanity, u.username, u.email, u.avatar
+        FROM tokens t JOIN users u ON u.vanity = t.user_vanity
+        WHERE t.token = $1 AND t.expire_at > NOW()
+        AND u.suspended_at IS NULL AND u.deleted_at IS NULL"#,
+        token,
+    )
+    .fetch_optional(&db.postgres)
+    .await?
+    .ok_or_else(|| Error::InvalidToken("Access token is invalid or expired.".into()))?;
+
+    let scope = row.scope.unwrap_or_default();
+    let granted = |name: &str| scope.split(' ').any(|scope| scope == name);
+
+    let mut response = Response {
+        sub: row.vanity,
+        ..Default::default()
+    };
+    if granted("profile") {
+        response.preferred_username = Some(row.username);
+        response.picture = row.avatar;
+    }
+    if granted("email") {
+        response.email = Some(crate::crypto::email_decryption(row.email));
+    }
+
+    Ok(Json(response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+    use axum::{
+        body::Body as RequestBody,
+        http::{self, Request, StatusCode},
+    };
+    use http_body_util::BodyExt;
+    use sqlx::{Pool, Postgres};
+    use tower::ServiceExt;
+
+    #[sqlx::test]
+    async fn test_userinfo_handler(pool: Pool<Postgres>) {
+        sqlx::query!(
+            r#"INSERT INTO "users" (vanity, username, email, password) values ($1, $2, $3, $4)"#,
+            "user",
+            "User",
+            crypto::email_encryption("test@gravitalia.com".into()),
+            "",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query!(
+            r#"INSERT INTO "clients" (client_id, registration_access_token, redirect_uris)
+            values ($1, $2, $3)"#,
+            "app",
+            "registration",
+            &["https://app.gravitalia.com/callback".to_string()],
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let token = user::User::default()
+            .with_vanity("user".into())
+            .get(&pool)
+            .await
+            .unwrap()
+            .generate_client_token(&pool, "app", "openid profile", "family")
+            .await
+            .unwrap();
+
+        let state = AppState {
+            db: database::Database {
+                postgres: pool.clone(),
+            },
+            config: status::Configuration::default(),
+            keys: jwt::KeyManager::default(),
+            mailer: std::sync::Arc::new(mail::Stdout),
+            captcha: None,
+        };
+        let app = app(state);
+
+        let request = || {
+            Request::builder()
+                .method(http::Method::GET)
+                .uri("/oauth/userinfo")
+                .header(http::header::AUTHORIZATION, format!("Bearer {}", token))
+                .body(RequestBody::empty())
+                .unwrap()
+        };
+        let response = app.clone().oneshot(request()).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Response = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body.sub, "user");
+        assert_eq!(body.preferred_username.as_deref(), Some("User"));
+        // `email` scope was not granted.
+        assert_eq!(body.email, None);
+
+        // Deleted users have no claims to share.
+        sqlx::query!(r#"UPDATE "users" SET deleted_at = CURRENT_DATE WHERE vanity = 'user'"#)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let response = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/oauth/userinfo")
+                    .header(http::header::AUTHORIZATION, "Bearer invalid")
+                    .body(RequestBody::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(response
+            .headers()
+            .contains_key(http::header::WWW_AUTHENTICATE));
+    }
+}
@@ -0,0 +1,489 @@
+//! Password policy.
+//!
+//! New passwords must be long enough, absent from the breach corpus, if any,
+//! and hard enough to guess according to a zxcvbn-like estimate.
+//!
+//! Reading the corpus may block, so it is checked by [`check_breach`] on the
+//! blocking pool rather than by the synchronous [`validate`].
+//!
+//! The corpus is read from `BREACHED_PASSWORDS`: either a directory of Have I
+//! Been Pwned range files, named after the first five hexadecimal characters
+//! of SHA-1 hashes, or a file of `HASH:COUNT` lines loaded into a bloom filter.
+
+use sha1::{Digest, Sha1};
+use validator::{ValidationError, ValidationErrors};
+
+use std::borrow::Cow;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+pub const MIN_LENGTH: usize = 8;
+/// Bounds hashing cost of overly long inputs.
+pub const MAX_LENGTH: usize = 128;
+/// Lowest accepted score, from 0 (too guessable) to 4 (very unguessable).
+pub const MIN_SCORE: u8 = 2;
+/// Bits of bloom filter per hash, for about 1% of false positives.
+const BITS_PER_HASH: usize = 10;
+const HASH_FUNCTIONS: usize = 7;
+
+static CORPUS: OnceLock<Corpus> = OnceLock::new();
+
+/// Common passwords and words, by decreasing frequency.
+const COMMON: &[&str] = &[
+    "password",
+    "123456",
+    "qwerty",
+    "azerty",
+    "letmein",
+    "iloveyou",
+    "admin",
+    "welcome",
+    "monkey",
+    "dragon",
+    "football",
+    "baseball",
+    "master",
+    "sunshine",
+    "princess",
+    "shadow",
+    "superman",
+    "trustno",
+    "michael",
+    "jordan",
+    "hello",
+    "freedom",
+    "whatever",
+    "starwars",
+    "computer",
+    "secret",
+    "login",
+    "access",
+    "charlie",
+    "soccer",
+    "hockey",
+    "batman",
+    "ninja",
+    "mustang",
+    "flower",
+    "cookie",
+    "pepper",
+    "ginger",
+    "jessica",
+    "ashley",
+    "bailey",
+    "summer",
+    "winter",
+    "spring",
+    "autumn",
+    "love",
+    "angel",
+    "tigger",
+    "hunter",
+    "buster",
+    "harley",
+    "ranger",
+    "thomas",
+    "robert",
+    "daniel",
+    "andrew",
+    "joshua",
+    "maggie",
+    "killer",
+    "cheese",
+    "orange",
+    "banana",
+    "apple",
+    "chocolate",
+    "matrix",
+    "pokemon",
+    "naruto",
+    "samsung",
+    "google",
+    "facebook",
+    "internet",
+    "monday",
+    "friday",
+    "family",
+    "forever",
+    "lovely",
+    "changeme",
+    "default",
+    "test",
+    "user",
+    "root",
+    "pass",
+    "gravitalia",
+    "autha",
+];
+
+/// Keyboard rows and keypads, walked to build spatial patterns.
+const KEYBOARDS: &[&str] = &[
+    "qwertyuiop",
+    "asdfghjkl",
+    "zxcvbnm",
+    "azertyuiop",
+    "qsdfghjklm",
+    "wxcvbn",
+    "1234567890",
+    "!@#$%^&*()",
+    "7894561230",
+];
+
+/// Breach corpus.
+pub enum Corpus {
+    /// Directory of range files.
+    Ranges(PathBuf),
+    Bloom(Bloom),
+}
+
+impl Corpus {
+    /// Open a directory of range files, or load a file of hashes.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        if path.is_dir() {
+            Ok(Corpus::Ranges(path.to_owned()))
+        } else {
+            Bloom::from_file(path).map(Corpus::Bloom)
+        }
+    }
+
+    /// Check whether a password appeared in a breach.
+    pub fn contains(&self, password: &str) -> io::Result<bool> {
+        let hash = Sha1::digest(password.as_bytes());
+
+        match self {
+            Corpus::Ranges(dir) => {
+                let hex = hex::encode_upper(hash);
+                let (prefix, suffix) = hex.split_at(5);
+                let path = [dir.join(prefix), dir.join(format!("{}.txt", prefix))]
+                    .into_iter()
+                    .find(|path| path.is_file());
+
+                let Some(path) = path else {
+                    return Ok(false);
+                };
+                for line in BufReader::new(File::open(path)?).lines() {
+                    let line = line?;
+                    let entry = line.split(':').next().unwrap_or_default().trim();
+                    // Some range files repeat the prefix.
+                    let entry = entry.get(entry.len().saturating_sub(35)..).unwrap_or(entry);
+                    if entry.eq_ignore_ascii_case(suffix) {
+                        return Ok(true);
+                    }
+                }
+
+                Ok(false)
+            }
+            Corpus::Bloom(bloom) => Ok(bloom.contains(&hash)),
+        }
+    }
+}
+
+/// Bloom filter of SHA-1 hashes.
+///
+/// Hashes are uniform already, so their bytes index the filter directly.
+pub struct Bloom {
+    bits: Vec<u64>,
+}
+
+impl Bloom {
+    pub fn new(capacity: usize) -> Self {
+        let len = (capacity.max(1) * BITS_PER_HASH).div_ceil(64);
+        Self { bits: vec![0; len] }
+    }
+
+    /// Load `HASH:COUNT` lines, such as those of the full Have I Been Pwned corpus.
+    pub fn from_file(path: &Path) -> io::Result<Self> {
+        // Lines are about 45 bytes long.
+        let capacity = fs::metadata(path)?.len() as usize / 40;
+        let mut bloom = Self::new(capacity);
+
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            let hex = line.split(':').next().unwrap_or_default().trim();
+            if let Ok(hash) = hex::decode(hex) {
+                if hash.len() == 20 {
+                    bloom.insert(&hash);
+                }
+            }
+        }
+
+        Ok(bloom)
+    }
+
+    fn positions(&self, hash: &[u8]) -> impl Iterator<Item = usize> {
+        // Double hashing from two halves of the hash.
+        let a = u64::from_le_bytes(hash[0..8].try_into().unwrap_or_default());
+        let b = u64::from_le_bytes(hash[8..16].try_into().unwrap_or_default()) | 1;
+        let len = self.bits.len() as u64 * 64;
+
+        (0..HASH_FUNCTIONS as u64).map(move |i| (a.wrapping_add(i.wrapping_mul(b)) % len) as usize)
+    }
+
+    pub fn insert(&mut self, hash: &[u8]) {
+        for position in self.positions(hash).collect::<Vec<_>>() {
+            self.bits[position / 64] |= 1 << (position % 64);
+        }
+    }
+
+    pub fn contains(&self, hash: &[u8]) -> bool {
+        self.positions(hash)
+            .all(|position| self.bits[position / 64] & (1 << (position % 64)) != 0)
+    }
+}
+
+/// Load the breach corpus from `BREACHED_PASSWORDS`, if set.
+pub fn load_from_env() -> io::Result<()> {
+    if let Ok(path) = std::env::var("BREACHED_PASSWORDS") {
+        let _ = CORPUS.set(Corpus::open(Path::new(&path))?);
+    }
+
+    Ok(())
+}
+
+/// Undo common character substitutions.
+fn unleet(c: char) -> char {
+    match c {
+        '@' | '4' => 'a',
+        '3' => 'e',
+        '1' | '!' => 'i',
+        '0' => 'o',
+        '$' | '5' => 's',
+        '7' => 't',
+        _ => c,
+    }
+}
+
+/// Estimated guesses of a dictionary word, with case and substitution variations.
+fn dictionary_guesses(token: &[char]) -> Option<f64> {
+    let lower: String = token.iter().flat_map(|c| c.to_lowercase()).collect();
+    let unleeted: String = lower.chars().map(unleet).collect();
+    let reversed: String = lower.chars().rev().collect();
+
+    let (rank, factor) = [(&lower, 1.0), (&unleeted, 2.0), (&reversed, 2.0)]
+        .into_iter()
+        .find_map(|(word, factor)| {
+            COMMON
+                .iter()
+                .position(|common| common == word)
+                .map(|rank| (rank, factor))
+        })?;
+
+    let uppercase = token.iter().filter(|c| c.is_uppercase()).count();
+    let case = if uppercase == 0 || (uppercase == 1 && token[0].is_uppercase()) {
+        1.0
+    } else if uppercase == token.len() {
+        2.0
+    } else {
+        2f64.powi(uppercase as i32)
+    };
+
+    Some((rank + 1) as f64 * factor * case)
+}
+
+/// Estimated guesses of a run of consecutive characters, such as `abcd` or `4321`.
+fn sequence_guesses(token: &[char]) -> Option<f64> {
+    if token.len() < 3 {
+        return None;
+    }
+    let delta = token[1] as i32 - token[0] as i32;
+    if delta.abs() != 1 || token.windows(2).any(|w| w[1] as i32 - w[0] as i32 != delta) {
+        return None;
+    }
+
+    let base = if matches!(token[0], 'a' | 'A' | 'z' | 'Z' | '0' | '1' | '9') {
+        4.0
+    } else if token[0].is_ascii_digit() {
+        10.0
+    } else {
+        26.0
+    };
+
+    Some(base * token.len() as f64 * if delta < 0 { 2.0 } else { 1.0 })
+}
+
+/// Estimated guesses of a repeated character, such as `aaaa`.
+fn repeat_guesses(token: &[char]) -> Option<f64> {
+    if token.len() < 3 || token.iter().any(|c| *c != token[0]) {
+        return None;
+    }
+
+    Some(cardinality(token[0]) * token.len() as f64)
+}
+
+/// Estimated guesses of a walk along a keyboard row.
+fn spatial_guesses(token: &[char]) -> Option<f64> {
+    if token.len() < 4 {
+        return None;
+    }
+    let lower: String = token.iter().flat_map(|c| c.to_lowercase()).collect();
+    let reversed: String = lower.chars().rev().collect();
+
+    KEYBOARDS
+        .iter()
+        .any(|row| row.contains(&lower) || row.contains(&reversed))
+        .then(|| (KEYBOARDS.len() * 2) as f64 * token.len() as f64)
+}
+
+/// Estimated guesses of a recent year.
+fn year_guesses(token: &[char]) -> Option<f64> {
+    let year: String = token.iter().collect();
+
+    (token.len() == 4 && matches!(year.parse::<u32>(), Ok(1900..=2049))).then_some(150.0)
+}
+
+fn cardinality(c: char) -> f64 {
+    if c.is_ascii_lowercase() || c.is_ascii_uppercase() {
+        26.0
+    } else if c.is_ascii_digit() {
+        10.0
+    } else {
+        33.0
+    }
+}
+
+/// Estimate guesses an attacker needs, as `log10`.
+///
+/// The password is split into the sequence of patterns that is cheapest to
+/// guess; characters matching no pattern are brute-forced.
+pub fn guesses_log10(password: &str) -> f64 {
+    let chars: Vec<char> = password.chars().collect();
+    let mut best = vec![f64::INFINITY; chars.len() + 1];
+    best[0] = 0.0;
+
+    for end in 1..=chars.len() {
+        // Brute force, about ten guesses per character as in zxcvbn.
+        best[end] = best[end - 1] + 1.0;
+
+        for start in 0..end - 1 {
+            let token = &chars[start..end];
+            let guesses = [
+                dictionary_guesses(token),
+                sequence_guesses(token),
+                repeat_guesses(token),
+                spatial_guesses(token),
+                year_guesses(token),
+            ]
+            .into_iter()
+            .flatten()
+            .fold(f64::INFINITY, f64::min);
+
+            // Each additional pattern is one more choice to guess.
+            let cost = best[start] + guesses.log10() + if start > 0 { 1.0 } else { 0.0 };
+            best[end] = best[end].min(cost);
+        }
+    }
+
+    best[chars.len()]
+}
+
+/// Strength score, from 0 (too guessable) to 4 (very unguessable), as zxcvbn.
+pub fn score(password: &str) -> u8 {
+    match guesses_log10(password) {
+        g if g < 3.0 => 0,
+        g if g < 6.0 => 1,
+        g if g < 8.0 => 2,
+        g if g < 10.0 => 3,
+        _ => 4,
+    }
+}
+
+fn error(code: &'static str, message: impl Into<Cow<'static, str>>) -> ValidationError {
+    ValidationError::new(code).with_message(message.into())
+}
+
+/// Validate a new password against the policy.
+pub fn validate(password: &str) -> Result<(), ValidationError> {
+    let length = password.chars().count();
+    if length < MIN_LENGTH {
+        return Err(error(
+            "length",
+            format!("Password must contain at least {} characters.", MIN_LENGTH),
+        ));
+    }
+    if length > MAX_LENGTH {
+        return Err(error(
+            "length",
+            format!("Password must contain at most {} characters.", MAX_LENGTH),
+        ));
+    }
+
+    if score(password) < MIN_SCORE {
+        return Err(error("weak_password", "Password is too easy to guess."));
+    }
+
+    Ok(())
+}
+
+/// Reject a new password, sent as `field`, that appeared in a data breach.
+pub async fn check_breach(field: &'static str, password: &str) -> Result<(), ValidationErrors> {
+    let Some(corpus) = CORPUS.get() else {
+        return Ok(());
+    };
+    let password = password.to_owned();
+
+    match tokio::task::spawn_blocking(move || corpus.contains(&password)).await {
+        Ok(Ok(true)) => {
+            let mut errors = ValidationErrors::new();
+            errors.add(
+                field,
+                error(
+                    "breached_password",
+                    "Password appeared in a data breach, choose another one.",
+                ),
+            );
+            Err(errors)
+        }
+        Ok(Ok(false)) => Ok(()),
+        // Strength estimate still applies.
+        Ok(Err(err)) => {
+            tracing::error!(error = ?err, "failed to read breach corpus");
+            Ok(())
+        }
+        Err(err) => {
+            tracing::error!(error = ?err, "breach corpus lookup panicked");
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_score() {
+        for password in [
+            "Password1234",
+            "qwerty123",
+            "aaaaaaaaaaaa",
+            "abcdefgh2024",
+            "P@ssw0rd!",
+        ] {
+            assert!(score(password) < MIN_SCORE, "{} is weak", password);
+        }
+        for password in ["Tr0mbone-Kayak-42", "correct horse battery staple"] {
+            assert!(score(password) >= MIN_SCORE, "{} is strong", password);
+        }
+    }
+
+    #[test]
+    fn test_corpus() {
+        let dir = std::env::temp_dir().join(format!("autha-breach-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        // SHA-1 of "Tr0mbone-Kayak-42".
+        let hash = hex::encode_upper(Sha1::digest(b"Tr0mbone-Kayak-42"));
+        fs::write(dir.join(&hash[..5]), format!("{}:3\r\n", &hash[5..])).unwrap();
+        let file = dir.join("hashes.txt");
+        fs::write(&file, format!("{}:3\n", hash)).unwrap();
+
+        for corpus in [Corpus::open(&dir).unwrap(), Corpus::open(&file).unwrap()] {
+            assert!(corpus.contains("Tr0mbone-Kayak-42").unwrap());
+            assert!(!corpus.contains("Cl4rinet-Canoe-17").unwrap());
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,404 @@
+//! Token-bucket rate limiting.
+//!
+//! Each request takes a token from up to three buckets: one per route, one per
+//! client IP on that route, and one per target account, identified by the
+//! encrypted `email` of JSON bodies.
+
+use async_trait::async_trait;
+use axum::body::{to_bytes, Body};
+use axum::extract::{ConnectInfo, MatchedPath, Request};
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Response};
+use serde::{Deserialize, Serialize};
+use tower::{Layer, Service};
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use crate::router::ServerError;
+
+/// Largest body read to find the target account.
+const MAX_BODY_SIZE: usize = 64 * 1024;
+/// Interval between drops of full buckets.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Errors that may occur while reaching a shared store.
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Rate limit store failed: {0}")]
+    Store(String),
+}
+
+/// Bucket size and refill rate.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Limit {
+    /// Requests allowed at once.
+    pub burst: u32,
+    /// Tokens added back every minute.
+    pub per_minute: u32,
+}
+
+impl Limit {
+    fn refill_rate(&self) -> f64 {
+        self.per_minute as f64 / 60.0
+    }
+}
+
+/// Limits of each key, `None` disabling it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct Limits {
+    pub ip: Option<Limit>,
+    pub account: Option<Limit>,
+    /// Shared by all clients: rejected requests do not drain it, but enough
+    /// distinct IPs and accounts still can, so keep it well above normal traffic.
+    pub route: Option<Limit>,
+    /// Read client IP from `X-Forwarded-For`, when behind a reverse proxy.
+    pub trust_proxy: bool,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            ip: Some(Limit {
+                burst: 10,
+                per_minute: 20,
+            }),
+            account: Some(Limit {
+                burst: 10,
+                per_minute: 10,
+            }),
+            route: Some(Limit {
+                burst: 200,
+                per_minute: 1200,
+            }),
+            trust_proxy: false,
+        }
+    }
+}
+
+/// Bucket storage, to be shared between instances for consistent limits.
+#[async_trait]
+pub trait Store: Send + Sync {
+    /// Take a token from bucket `key`, returning how long to wait if it is empty.
+    async fn take(&self, key: &str, limit: Limit) -> Result<Option<Duration>, Error>;
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+    /// When the bucket is full again, `None` if it never refills.
+    full_at: Option<Instant>,
+}
+
+struct Buckets {
+    map: HashMap<String, Bucket>,
+    swept_at: Instant,
+}
+
+/// Store keeping buckets in process memory.
+pub struct Memory {
+    buckets: Mutex<Buckets>,
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self {
+            buckets: Mutex::new(Buckets {
+                map: HashMap::new(),
+                swept_at: Instant::now(),
+            }),
+        }
+    }
+}
+
+#[async_trait]
+impl Store for Memory {
+    async fn take(&self, key: &str, limit: Limit) -> Result<Option<Duration>, Error> {
+        let mut buckets = self
+            .buckets
+            .lock()
+            .map_err(|err| Error::Store(err.to_string()))?;
+        let now = Instant::now();
+        let rate = limit.refill_rate();
+        let capacity = limit.burst as f64;
+
+        // Full buckets hold no information.
+        if now.duration_since(buckets.swept_at) >= SWEEP_INTERVAL {
+            buckets
+                .map
+                .retain(|_, bucket| bucket.full_at.is_none_or(|full_at| full_at > now));
+            buckets.swept_at = now;
+        }
+
+        let bucket = buckets.map.entry(key.to_owned()).or_insert(Bucket {
+            tokens: capacity,
+            updated_at: now,
+            full_at: Some(now),
+        });
+        bucket.tokens = (bucket.tokens
+            + now.duration_since(bucket.updated_at).as_secs_f64() * rate)
+            .min(capacity);
+        bucket.updated_at = now;
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        bucket.full_at = if rate > 0.0 {
+            now.checked_add(Duration::from_secs_f64((capacity - bucket.tokens) / rate))
+        } else {
+            None
+        };
+
+        if allowed {
+            Ok(None)
+        } else if rate > 0.0 {
+            Ok(Some(Duration::from_secs_f64((1.0 - bucket.tokens) / rate)))
+        } else {
+            Ok(Some(Duration::MAX))
+        }
+    }
+}
+
+/// Layer applying [`Limits`] to a service.
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    limits: Arc<Limits>,
+    store: Arc<dyn Store>,
+}
+
+impl RateLimitLayer {
+    pub fn new(limits: Limits, store: Arc<dyn Store>) -> Self {
+        Self {
+            limits: Arc::new(limits),
+            store,
+        }
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit {
+            inner,
+            limits: self.limits.clone(),
+            store: self.store.clone(),
+        }
+    }
+}
+
+/// Service rejecting requests once a bucket is empty.
+#[derive(Clone)]
+pub struct RateLimit<S> {
+    inner: S,
+    limits: Arc<Limits>,
+    store: Arc<dyn Store>,
+}
+
+/// Find client IP, from the connection or the reverse proxy.
+///
+/// Clients can send their own `X-Forwarded-For`, so only the last entry,
+/// appended by the trusted proxy, is used.
+fn client_ip(request: &Request, trust_proxy: bool) -> Option<IpAddr> {
+    let forwarded = |headers: &HeaderMap| {
+        headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .next_back()
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.rsplit(',').next())
+            .and_then(|ip| ip.trim().parse().ok())
+    };
+
+    if trust_proxy {
+        if let Some(ip) = forwarded(request.headers()) {
+            return Some(ip);
+        }
+    }
+
+    request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip())
+}
+
+/// Find the target account of a JSON body.
+fn account(body: &[u8]) -> Option<String> {
+    let value: serde_json::Value = serde_json::from_slice(body).ok()?;
+    let email = value.get("email")?.as_str()?;
+
+    Some(crate::crypto::email_encryption(email.to_lowercase()))
+}
+
+impl<S> Service<Request> for RateLimit<S>
+where
+    S: Service<Request, Response = Response> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        // Use the service that was driven to readiness.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let limits = self.limits.clone();
+        let store = self.store.clone();
+
+        Box::pin(async move {
+            let route = request
+                .extensions()
+                .get::<MatchedPath>()
+                .map(|path| path.as_str().to_owned())
+                .unwrap_or_else(|| request.uri().path().to_owned());
+            let ip = client_ip(&request, limits.trust_proxy);
+
+            let (parts, body) = request.into_parts();
+            let (request, account) = if limits.account.is_some() {
+                let Ok(bytes) = to_bytes(body, MAX_BODY_SIZE).await else {
+                    return Ok(ServerError::PayloadTooLarge.into_response());
+                };
+                let account = account(&bytes);
+                (Request::from_parts(parts, Body::from(bytes)), account)
+            } else {
+                (Request::from_parts(parts, body), None)
+            };
+
+            // Route bucket comes last, so rejected clients cannot drain it.
+            let buckets = [
+                limits
+                    .ip
+                    .zip(ip)
+                    .map(|(limit, ip)| (format!("ip:{}:{}", route, ip), limit)),
+                limits
+                    .account
+                    .zip(account)
+                    .map(|(limit, account)| (format!("account:{}", account), limit)),
+                limits
+                    .route
+                    .map(|limit| (format!("route:{}", route), limit)),
+            ];
+
+            for (key, limit) in buckets.into_iter().flatten() {
+                match store.take(&key, limit).await {
+                    Ok(None) => {}
+                    Ok(Some(retry_after)) => {
+                        return Ok(ServerError::TooManyRequests(retry_after).into_response())
+                    }
+                    // Availability matters more than limits.
+                    Err(err) => tracing::error!(error = ?err, "rate limit store failed"),
+                }
+            }
+
+            inner.call(request).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::{self, header, StatusCode};
+    use axum::{routing::post, Router};
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let limits = Limits {
+            ip: None,
+            account: Some(Limit {
+                burst: 2,
+                per_minute: 1,
+            }),
+            route: None,
+            trust_proxy: false,
+        };
+        let app = Router::new()
+            .route("/login", post(|| async { StatusCode::OK }))
+            .route_layer(RateLimitLayer::new(limits, Arc::new(Memory::default())));
+
+        let request = |email: &str| {
+            Request::builder()
+                .method(http::Method::POST)
+                .uri("/login")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    serde_json::json!({ "email": email }).to_string(),
+                ))
+                .unwrap()
+        };
+
+        for status in [
+            StatusCode::OK,
+            StatusCode::OK,
+            StatusCode::TOO_MANY_REQUESTS,
+        ] {
+            let response = app
+                .clone()
+                .oneshot(request("test@gravitalia.com"))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), status);
+
+            if status == StatusCode::TOO_MANY_REQUESTS {
+                let retry_after = response.headers()[header::RETRY_AFTER].to_str().unwrap();
+                assert!((1..=60).contains(&retry_after.parse::<u64>().unwrap()));
+
+                let body = response.into_body().collect().await.unwrap().to_bytes();
+                let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                assert_eq!(body["status"], 429);
+            }
+        }
+
+        // Other accounts have their own bucket.
+        let response = app
+            .clone()
+            .oneshot(request("other@gravitalia.com"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app
+            .oneshot(request(&"a".repeat(MAX_BODY_SIZE)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["status"], 413);
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let mut request = Request::builder()
+            .header("x-forwarded-for", "198.51.100.1, 203.0.113.7")
+            .body(Body::empty())
+            .unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([10, 0, 0, 1], 443))));
+
+        // Leftmost entries come from the client.
+        assert_eq!(
+            client_ip(&request, true),
+            Some(IpAddr::from([203, 0, 113, 7]))
+        );
+        assert_eq!(
+            client_ip(&request, false),
+            Some(IpAddr::from([10, 0, 0, 1]))
+        );
+    }
+}
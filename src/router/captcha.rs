@@ -0,0 +1,151 @@
+//! Captcha challenges and enforcement.
+
+use axum::{extract::State, Json};
+use validator::{ValidationError, ValidationErrors};
+
+use crate::captcha::{Challenge, SharedVerifier};
+
+use super::ServerError;
+
+/// Issue a proof-of-work challenge.
+///
+/// Not found when captchas are disabled or handled by a third party.
+pub async fn challenge(
+    State(verifier): State<SharedVerifier>,
+) -> Result<Json<Challenge>, ServerError> {
+    let verifier = verifier.ok_or(ServerError::NotFound)?;
+
+    match verifier.challenge().await {
+        Ok(Some(challenge)) => Ok(Json(challenge)),
+        Ok(None) => Err(ServerError::NotFound),
+        Err(err) => Err(ServerError::Internal(err.to_string())),
+    }
+}
+
+/// Require a solved captcha, if captchas are enabled.
+pub(super) async fn require(
+    verifier: &SharedVerifier,
+    response: Option<&str>,
+) -> Result<(), ServerError> {
+    let Some(verifier) = verifier else {
+        return Ok(());
+    };
+
+    let solved = match response {
+        Some(response) => verifier
+            .verify(response)
+            .await
+            .map_err(|err| ServerError::Internal(err.to_string()))?,
+        None => false,
+    };
+
+    if !solved {
+        let error = ValidationError::new("invalid_captcha")
+            .with_message("Captcha is missing or invalid.".into());
+        let mut errors = ValidationErrors::new();
+        errors.add("captcha", error);
+        return Err(errors.into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::captcha::{solve, Challenge, ProofOfWork};
+    use crate::*;
+    use axum::{
+        body::Body as RequestBody,
+        http::{self, Request, StatusCode},
+    };
+    use http_body_util::BodyExt;
+    use sqlx::{Pool, Postgres};
+    use tower::ServiceExt;
+
+    use std::sync::Arc;
+
+    #[sqlx::test]
+    async fn test_captcha(pool: Pool<Postgres>) {
+        let state = AppState {
+            db: database::Database {
+                postgres: pool.clone(),
+            },
+            config: status::Configuration::default(),
+            keys: jwt::KeyManager::default(),
+            mailer: Arc::new(mail::Stdout),
+            captcha: Some(Arc::new(ProofOfWork::new(pool, 8))),
+        };
+        let app = app(state);
+
+        let request = |uri: &str, body: serde_json::Value| {
+            Request::builder()
+                .method(http::Method::POST)
+                .uri(uri)
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(RequestBody::from(body.to_string()))
+                .unwrap()
+        };
+        let solved = || async {
+            let response = app
+                .clone()
+                .oneshot(request("/captcha/challenge", serde_json::json!({})))
+                .await
+                .unwrap();
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            let challenge: Challenge = serde_json::from_slice(&body).unwrap();
+            solve(&challenge)
+        };
+
+        let mut body = serde_json::json!({
+            "vanity": "user",
+            "email": "test@gravitalia.com",
+            "password": "Tr0mbone-Kayak-42",
+        });
+        let response = app
+            .clone()
+            .oneshot(request("/create", body.clone()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        body["captcha"] = solved().await.into();
+        let response = app
+            .clone()
+            .oneshot(request("/create", body.clone()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        // Solutions are single-use.
+        body["vanity"] = "other".into();
+        body["email"] = "other@gravitalia.com".into();
+        let response = app.clone().oneshot(request("/create", body)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // Login asks for a captcha after repeated failures only.
+        for password in ["Wrong1234", "Wrong1234", "Wrong1234", "Tr0mbone-Kayak-42"] {
+            let response = app
+                .clone()
+                .oneshot(request(
+                    "/login",
+                    serde_json::json!({ "email": "test@gravitalia.com", "password": password }),
+                ))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+        let response = app
+            .clone()
+            .oneshot(request(
+                "/login",
+                serde_json::json!({
+                    "email": "test@gravitalia.com",
+                    "password": "Tr0mbone-Kayak-42",
+                    "captcha": solved().await,
+                }),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
@@ -1,12 +1,11 @@
-use argon2::{
-    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, SaltString},
-    Argon2, Params, Version,
-};
 use axum::{extract::State, http::StatusCode, Json};
 use serde::{Deserialize, Serialize};
-use validator::Validate;
+use validator::{Validate, ValidationError, ValidationErrors};
 
-use crate::{database::Database, user::User};
+use crate::{
+    captcha::SharedVerifier, database::Database, mail::SharedMailer, status::Configuration,
+    user::User,
+};
 
 use super::{ServerError, Valid};
 
@@ -16,9 +15,11 @@ pub struct Body {
     vanity: String,
     #[validate(email(message = "Email must be formated."))]
     email: String,
-    #[validate(length(min = 8, message = "Password must contain at least 8 characters."))]
+    #[validate(custom(function = "crate::password::validate"))]
     password: String,
-    _captcha: Option<String>,
+    invite: Option<String>,
+    #[serde(alias = "_captcha")]
+    captcha: Option<String>,
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
@@ -29,25 +30,23 @@ pub struct Response {
 
 pub async fn create(
     State(db): State<Database>,
+    State(config): State<Configuration>,
+    State(mailer): State<SharedMailer>,
+    State(captcha): State<SharedVerifier>,
     Valid(body): Valid<Body>,
 ) -> Result<(StatusCode, Json<Response>), ServerError> {
-    let email = crate::crypto::email_encryption(body.email);
+    super::captcha::require(&captcha, body.captcha.as_deref()).await?;
+    crate::password::check_breach("password", &body.password).await?;
 
-    let password = {
-        let salt = SaltString::generate(&mut OsRng);
-        let params = Params::new(Params::DEFAULT_M_COST * 4, 6, Params::DEFAULT_P_COST, None)
-            .map_err(|err| ServerError::Internal(err.to_string()))?;
-        let argon2 = Argon2::new(argon2::Algorithm::Argon2id, Version::V0x13, params);
+    let email = crate::crypto::email_encryption(body.email);
 
-        let password_hash = argon2
-            .hash_password(body.password.as_bytes(), &salt)
-            .map_err(|err| ServerError::Internal(err.to_string()))?
-            .to_string();
-        let hash = PasswordHash::new(&password_hash)
+    let password = body.password.clone();
+    let password =
+        crate::crypto::blocking(move || crate::crypto::hash_password(password.as_bytes()))
+            .await
             .map_err(|err| ServerError::Internal(err.to_string()))?;
 
-        hash.to_string()
-    };
+    let mut tx = db.postgres.begin().await?;
 
     sqlx::query!(
         r#"INSERT INTO "users" (vanity, username, email, password) values ($1, $2, $3, $4)"#,
@@ -56,15 +55,38 @@ pub async fn create(
         email,
         password
     )
-    .execute(&db.postgres)
+    .execute(&mut *tx)
     .await?;
 
+    // Claim the invitation with the user, so a code cannot be used twice.
+    if config.invite_only {
+        let claimed = match &body.invite {
+            Some(code) => super::invites::claim(&mut tx, code, &body.vanity.to_lowercase()).await?,
+            None => false,
+        };
+
+        if !claimed {
+            let error = ValidationError::new("invalid_invite")
+                .with_message("A valid invitation code is required.".into());
+            let mut errors = ValidationErrors::new();
+            errors.add("invite", error);
+            return Err(errors.into());
+        }
+    }
+
+    tx.commit().await?;
+
     let user = User::default()
         .with_vanity(body.vanity.to_lowercase())
         .get(&db.postgres)
         .await?;
     let token = user.generate_token(&db.postgres).await?;
 
+    // Account stays usable, verification can be requested again.
+    if let Err(err) = super::email::send_verification(&db, mailer.as_ref(), &user).await {
+        tracing::error!(error = ?err, "failed to send verification email");
+    }
+
     Ok((StatusCode::CREATED, Json(Response {
         user,
         token,
@@ -88,14 +110,18 @@ mod tests {
         let state = AppState {
             db: Database { postgres: pool },
             config: status::Configuration::default(),
+            keys: jwt::KeyManager::default(),
+            mailer: std::sync::Arc::new(mail::Stdout),
+            captcha: None,
         };
         let app = app(state);
 
         let body = Body {
             vanity: "user".into(),
             email: "test@gravitalia.com".into(),
-            password: "Password1234".into(),
-            _captcha: None,
+            password: "Tr0mbone-Kayak-42".into(),
+            invite: None,
+            captcha: None,
         };
         let body = serde_json::to_string(&body).unwrap();
         let response = app
@@ -119,4 +145,65 @@ mod tests {
         assert!(body.token.is_ascii());
         assert_eq!(body.user.vanity, "user");
     }
+
+    #[sqlx::test]
+    async fn test_invite_only(pool: Pool<Postgres>) {
+        sqlx::query!(r#"INSERT INTO "invite_codes" (code) values ($1)"#, "welcome")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let mut config = status::Configuration::default();
+        config.invite_only = true;
+        let state = AppState {
+            db: Database {
+                postgres: pool.clone(),
+            },
+            config,
+            keys: jwt::KeyManager::default(),
+            mailer: std::sync::Arc::new(mail::Stdout),
+            captcha: None,
+        };
+        let app = app(state);
+
+        let request = |vanity: &str, invite: Option<&str>| {
+            let body = Body {
+                vanity: vanity.into(),
+                email: format!("{}@gravitalia.com", vanity),
+                password: "Tr0mbone-Kayak-42".into(),
+                invite: invite.map(Into::into),
+                captcha: None,
+            };
+            Request::builder()
+                .method(http::Method::POST)
+                .uri("/create")
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(RequestBody::from(serde_json::to_string(&body).unwrap()))
+                .unwrap()
+        };
+
+        for (vanity, invite, status) in [
+            ("first", None, StatusCode::BAD_REQUEST),
+            ("first", Some("unknown"), StatusCode::BAD_REQUEST),
+            ("first", Some("welcome"), StatusCode::CREATED),
+            ("second", Some("welcome"), StatusCode::BAD_REQUEST),
+        ] {
+            let response = app.clone().oneshot(request(vanity, invite)).await.unwrap();
+            assert_eq!(response.status(), status);
+        }
+
+        // Rejected sign-ups leave no account behind.
+        let used_by = sqlx::query_scalar!(
+            r#"SELECT used_by FROM "invite_codes" WHERE code = 'welcome'"#
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(used_by.as_deref(), Some("first"));
+        assert!(user::User::default()
+            .with_vanity("second".into())
+            .get(&pool)
+            .await
+            .is_err());
+    }
 }
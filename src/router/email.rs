@@ -0,0 +1,209 @@
+//! Email address verification.
+
+use axum::{extract::State, http::StatusCode};
+use rand::distributions::{Alphanumeric, DistString};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use validator::{Validate, ValidationError, ValidationErrors};
+
+use crate::mail::{Email, Mailer, SharedMailer};
+use crate::{database::Database, user::User};
+
+use super::{Authenticated, ServerError, Valid};
+
+const TOKEN_LENGTH: usize = 32;
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct Body {
+    #[validate(length(equal = 32, message = "Token must contain 32 characters."))]
+    token: String,
+}
+
+/// Email a verification token for the current address of `user`.
+pub async fn send_verification(
+    db: &Database,
+    mailer: &dyn Mailer,
+    user: &User,
+) -> Result<(), ServerError> {
+    let token = Alphanumeric.sample_string(&mut OsRng, TOKEN_LENGTH);
+
+    sqlx::query!(
+        r#"INSERT INTO "email_verifications" (token, user_vanity, email) values ($1, $2, $3)"#,
+        crate::crypto::hash_token(&token),
+        user.vanity,
+        user.email,
+    )
+    .execute(&db.postgres)
+    .await?;
+
+    mailer
+        .send(Email {
+            to: crate::crypto::email_decryption(user.email.clone()),
+            subject: "Verify your email address".into(),
+            body: format!(
+                "Hello {},\n\nUse this code to verify your email address: {}\n\nIt expires in 24 hours.",
+                user.username, token
+            ),
+        })
+        .await
+        .map_err(|err| ServerError::Internal(err.to_string()))
+}
+
+/// Send a new verification token, such as when the first email got lost.
+pub async fn resend(
+    State(db): State<Database>,
+    State(mailer): State<SharedMailer>,
+    Authenticated(user): Authenticated,
+) -> Result<StatusCode, ServerError> {
+    if user.flags & crate::user::FLAG_VERIFIED_EMAIL != 0 {
+        let error = ValidationError::new("already_verified")
+            .with_message("Email address is already verified.".into());
+        let mut errors = ValidationErrors::new();
+        errors.add("email", error);
+        return Err(errors.into());
+    }
+
+    send_verification(&db, mailer.as_ref(), &user).await?;
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Mark the email address of the authenticated user as verified.
+pub async fn verify(
+    State(db): State<Database>,
+    Authenticated(user): Authenticated,
+    Valid(body): Valid<Body>,
+) -> Result<StatusCode, ServerError> {
+    // Tokens sent to a previous address are not valid anymore.
+    let consumed = sqlx::query!(
+        r#"DELETE FROM "email_verifications"
+        WHERE token = $1 AND user_vanity = $2 AND email = $3 AND expire_at > NOW()"#,
+        crate::crypto::hash_token(&body.token),
+        user.vanity,
+        user.email,
+    )
+    .execute(&db.postgres)
+    .await?
+    .rows_affected();
+
+    if consumed != 1 {
+        let error = ValidationError::new("invalid_token")
+            .with_message("Token is invalid or expired.".into());
+        let mut errors = ValidationErrors::new();
+        errors.add("token", error);
+        return Err(errors.into());
+    }
+
+    sqlx::query!(
+        r#"UPDATE "users" SET flags = flags | $2 WHERE vanity = $1"#,
+        user.vanity,
+        crate::user::FLAG_VERIFIED_EMAIL,
+    )
+    .execute(&db.postgres)
+    .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mail::Outbox;
+    use crate::*;
+    use axum::{
+        body::Body as RequestBody,
+        http::{self, Request, StatusCode},
+    };
+    use http_body_util::BodyExt;
+    use sqlx::{Pool, Postgres};
+    use tower::ServiceExt;
+
+    use std::sync::Arc;
+
+    #[sqlx::test]
+    async fn test_verify_handler(pool: Pool<Postgres>) {
+        let outbox = Arc::new(Outbox::default());
+        let state = AppState {
+            db: database::Database {
+                postgres: pool.clone(),
+            },
+            config: status::Configuration::default(),
+            keys: jwt::KeyManager::default(),
+            mailer: outbox.clone(),
+            captcha: None,
+        };
+        let app = app(state);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/create")
+                    .header(http::header::CONTENT_TYPE, "application/json")
+                    .body(RequestBody::from(
+                        serde_json::json!({
+                            "vanity": "user",
+                            "email": "test@gravitalia.com",
+                            "password": "Tr0mbone-Kayak-42",
+                        })
+                        .to_string(),
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let token = body["token"].as_str().unwrap().to_owned();
+
+        let email = outbox.0.lock().unwrap().pop().unwrap();
+        assert_eq!(email.to, "test@gravitalia.com");
+
+        let resend = || {
+            Request::builder()
+                .method(http::Method::POST)
+                .uri("/users/@me/email/verification")
+                .header(http::header::AUTHORIZATION, format!("Bearer {}", token))
+                .body(RequestBody::empty())
+                .unwrap()
+        };
+        let response = app.clone().oneshot(resend()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let resent = outbox.0.lock().unwrap().pop().unwrap();
+        assert_eq!(resent.to, email.to);
+
+        let code = email
+            .body
+            .split_whitespace()
+            .find(|word| word.len() == 32)
+            .unwrap()
+            .to_owned();
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/users/@me/email/verify")
+                    .header(http::header::AUTHORIZATION, format!("Bearer {}", token))
+                    .header(http::header::CONTENT_TYPE, "application/json")
+                    .body(RequestBody::from(
+                        serde_json::json!({ "token": code }).to_string(),
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let user = user::User::from_token(&pool, &token).await.unwrap();
+        assert_eq!(
+            user.flags & user::FLAG_VERIFIED_EMAIL,
+            user::FLAG_VERIFIED_EMAIL
+        );
+
+        let response = app.oneshot(resend()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,500 @@
+//! Invitation codes, minted by administrators or by members within their quota.
+
+use axum::extract::{Path, State};
+use axum::{http::StatusCode, Json};
+use rand::distributions::{Alphanumeric, DistString};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+use validator::Validate;
+
+use std::collections::HashMap;
+
+use crate::database::Database;
+
+use super::{Admin, Authenticated, ServerError, Valid};
+
+const CODE_LENGTH: usize = 16;
+
+/// Invitation code and its redemptions.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Invite {
+    pub code: String,
+    /// User who issued the code.
+    pub created_by: Option<String>,
+    pub max_uses: i32,
+    pub uses: i32,
+    /// Expiration, as a UNIX timestamp.
+    pub expire_at: Option<i64>,
+    /// Latest user who signed up with the code.
+    pub used_by: Option<String>,
+    pub used_at: Option<i64>,
+    /// Users who signed up with the code, oldest first.
+    pub redemptions: Vec<Redemption>,
+}
+
+/// Sign-up with an invitation code.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Redemption {
+    pub vanity: String,
+    /// Sign-up time, as a UNIX timestamp.
+    pub used_at: i64,
+}
+
+/// Invitation code, as stored.
+struct Code {
+    code: String,
+    created_by: Option<String>,
+    max_uses: i32,
+    uses: i32,
+    expire_at: Option<i64>,
+    used_by: Option<String>,
+    used_at: Option<i64>,
+}
+
+impl From<Code> for Invite {
+    fn from(code: Code) -> Self {
+        Self {
+            code: code.code,
+            created_by: code.created_by,
+            max_uses: code.max_uses,
+            uses: code.uses,
+            expire_at: code.expire_at,
+            used_by: code.used_by,
+            used_at: code.used_at,
+            redemptions: Vec::new(),
+        }
+    }
+}
+
+/// Batch of invitation codes to mint.
+#[derive(Debug, Default, Serialize, Deserialize, Validate)]
+pub struct Batch {
+    #[validate(range(min = 1, max = 100, message = "Count must be between 1 and 100."))]
+    count: i32,
+    #[validate(range(min = 1, message = "Codes must allow at least one use."))]
+    max_uses: Option<i32>,
+    /// Lifetime of the codes, in seconds.
+    #[validate(range(min = 60, message = "Codes must live at least one minute."))]
+    expires_in: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct Quota {
+    #[validate(range(min = 0, max = 1000, message = "Quota must be between 0 and 1000."))]
+    quota: i32,
+}
+
+/// Invitations issued by the authenticated user.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Invitations {
+    /// Invitations the user can still issue.
+    pub quota: i32,
+    pub invites: Vec<Invite>,
+}
+
+/// Attach their redemptions to `codes`.
+async fn with_redemptions(
+    conn: &mut PgConnection,
+    codes: Vec<Code>,
+) -> Result<Vec<Invite>, sqlx::Error> {
+    let mut invites: Vec<Invite> = codes.into_iter().map(Invite::from).collect();
+    let index: HashMap<String, usize> = invites
+        .iter()
+        .enumerate()
+        .map(|(i, invite)| (invite.code.clone(), i))
+        .collect();
+
+    let redemptions = sqlx::query!(
+        r#"SELECT code, vanity, EXTRACT(EPOCH FROM used_at)::BIGINT AS "used_at!"
+        FROM "invite_redemptions" WHERE code = ANY($1) ORDER BY used_at, vanity"#,
+        &index.keys().cloned().collect::<Vec<_>>(),
+    )
+    .fetch_all(conn)
+    .await?;
+    for redemption in redemptions {
+        if let Some(&i) = index.get(&redemption.code) {
+            invites[i].redemptions.push(Redemption {
+                vanity: redemption.vanity,
+                used_at: redemption.used_at,
+            });
+        }
+    }
+
+    Ok(invites)
+}
+
+/// Insert new codes, returning them.
+async fn mint(
+    conn: &mut PgConnection,
+    created_by: &str,
+    count: usize,
+    max_uses: i32,
+    expires_in: Option<i64>,
+) -> Result<Vec<Invite>, sqlx::Error> {
+    let codes: Vec<String> = (0..count)
+        .map(|_| Alphanumeric.sample_string(&mut OsRng, CODE_LENGTH))
+        .collect();
+
+    Ok(sqlx::query_as!(
+        Code,
+        r#"INSERT INTO "invite_codes" (code, created_by, max_uses, expire_at)
+        SELECT code, $2, $3, NOW() + make_interval(secs => $4) FROM UNNEST($1::TEXT[]) AS code
+        RETURNING code, created_by, max_uses, uses, EXTRACT(EPOCH FROM expire_at)::BIGINT AS expire_at,
+        used_by, EXTRACT(EPOCH FROM used_at)::BIGINT AS used_at"#,
+        &codes,
+        created_by,
+        max_uses,
+        expires_in.map(|secs| secs as f64),
+    )
+    .fetch_all(conn)
+    .await?
+    .into_iter()
+    .map(Invite::from)
+    .collect())
+}
+
+/// Redeem a code for `vanity`, returning whether it was still valid.
+///
+/// Must run in the transaction creating the user.
+pub(super) async fn claim(
+    conn: &mut PgConnection,
+    code: &str,
+    vanity: &str,
+) -> Result<bool, sqlx::Error> {
+    let claimed = sqlx::query_scalar!(
+        r#"UPDATE "invite_codes" SET uses = uses + 1, used_by = $1, used_at = NOW()
+        WHERE code = $2 AND uses < max_uses AND (expire_at IS NULL OR expire_at > NOW())
+        RETURNING code"#,
+        vanity,
+        code,
+    )
+    .fetch_optional(&mut *conn)
+    .await?
+    .is_some();
+
+    if claimed {
+        sqlx::query!(
+            r#"INSERT INTO "invite_redemptions" (code, vanity) values ($1, $2)"#,
+            code,
+            vanity,
+        )
+        .execute(conn)
+        .await?;
+    }
+
+    Ok(claimed)
+}
+
+/// Mint a batch of invitation codes.
+pub async fn create_batch(
+    State(db): State<Database>,
+    Admin(admin): Admin,
+    Valid(body): Valid<Batch>,
+) -> Result<(StatusCode, Json<Vec<Invite>>), ServerError> {
+    let mut conn = db.postgres.acquire().await?;
+    let invites = mint(
+        &mut conn,
+        &admin.vanity,
+        body.count as usize,
+        body.max_uses.unwrap_or(1),
+        body.expires_in,
+    )
+    .await?;
+
+    Ok((StatusCode::CREATED, Json(invites)))
+}
+
+/// List every invitation code, showing who invited whom.
+pub async fn list(
+    State(db): State<Database>,
+    Admin(_): Admin,
+) -> Result<Json<Vec<Invite>>, ServerError> {
+    let mut conn = db.postgres.acquire().await?;
+    let codes = sqlx::query_as!(
+        Code,
+        r#"SELECT code, created_by, max_uses, uses, EXTRACT(EPOCH FROM expire_at)::BIGINT AS expire_at,
+        used_by, EXTRACT(EPOCH FROM used_at)::BIGINT AS used_at
+        FROM "invite_codes" ORDER BY created_at DESC, code"#,
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    Ok(Json(with_redemptions(&mut conn, codes).await?))
+}
+
+/// Set how many invitations a member can still issue.
+pub async fn set_quota(
+    State(db): State<Database>,
+    Admin(_): Admin,
+    Path(vanity): Path<String>,
+    Valid(body): Valid<Quota>,
+) -> Result<StatusCode, ServerError> {
+    let result = sqlx::query!(
+        r#"UPDATE "users" SET invite_quota = $2 WHERE vanity = $1 AND deleted_at IS NULL"#,
+        vanity,
+        body.quota,
+    )
+    .execute(&db.postgres)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(ServerError::NotFound);
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// List invitations issued by the authenticated user.
+pub async fn mine(
+    State(db): State<Database>,
+    Authenticated(user): Authenticated,
+) -> Result<Json<Invitations>, ServerError> {
+    let mut conn = db.postgres.acquire().await?;
+    let quota = sqlx::query_scalar!(
+        r#"SELECT invite_quota FROM "users" WHERE vanity = $1"#,
+        user.vanity
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+    let codes = sqlx::query_as!(
+        Code,
+        r#"SELECT code, created_by, max_uses, uses, EXTRACT(EPOCH FROM expire_at)::BIGINT AS expire_at,
+        used_by, EXTRACT(EPOCH FROM used_at)::BIGINT AS used_at
+        FROM "invite_codes" WHERE created_by = $1 ORDER BY created_at DESC, code"#,
+        user.vanity,
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    let invites = with_redemptions(&mut conn, codes).await?;
+
+    Ok(Json(Invitations { quota, invites }))
+}
+
+/// Issue a single-use invitation, consuming one unit of quota.
+pub async fn create_mine(
+    State(db): State<Database>,
+    Authenticated(user): Authenticated,
+) -> Result<(StatusCode, Json<Invite>), ServerError> {
+    let mut tx = db.postgres.begin().await?;
+
+    let result = sqlx::query!(
+        r#"UPDATE "users" SET invite_quota = invite_quota - 1 WHERE vanity = $1 AND invite_quota > 0"#,
+        user.vanity,
+    )
+    .execute(&mut *tx)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Err(ServerError::Forbidden);
+    }
+
+    let invite = mint(&mut tx, &user.vanity, 1, 1, None)
+        .await?
+        .pop()
+        .ok_or_else(|| ServerError::Internal("no invitation minted".into()))?;
+
+    tx.commit().await?;
+
+    Ok((StatusCode::CREATED, Json(invite)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+    use axum::{
+        body::Body as RequestBody,
+        http::{self, Request, StatusCode},
+    };
+    use http_body_util::BodyExt;
+    use sqlx::{Pool, Postgres};
+    use tower::ServiceExt;
+
+    #[sqlx::test]
+    async fn test_invites(pool: Pool<Postgres>) {
+        let mut tokens = Vec::new();
+        for (vanity, flags) in [("admin", user::FLAG_ADMIN), ("member", 0)] {
+            sqlx::query!(
+                r#"INSERT INTO "users" (vanity, username, email, password, flags) values ($1, $1, $2, $3, $4)"#,
+                vanity,
+                crypto::email_encryption(format!("{}@gravitalia.com", vanity)),
+                crypto::hash_password(b"Password1234").unwrap(),
+                flags,
+            )
+            .execute(&pool)
+            .await
+            .unwrap();
+            let token = user::User::default()
+                .with_vanity(vanity.into())
+                .get(&pool)
+                .await
+                .unwrap()
+                .generate_token(&pool)
+                .await
+                .unwrap();
+            tokens.push(token);
+        }
+        let (admin, member) = (&tokens[0], &tokens[1]);
+
+        let mut config = status::Configuration::default();
+        config.invite_only = true;
+        let state = AppState {
+            db: database::Database {
+                postgres: pool.clone(),
+            },
+            config,
+            keys: jwt::KeyManager::default(),
+            mailer: std::sync::Arc::new(mail::Stdout),
+            captcha: None,
+        };
+        let app = app(state);
+
+        let request = |method: http::Method, uri: &str, token: &str, body: serde_json::Value| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(http::header::AUTHORIZATION, format!("Bearer {}", token))
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(RequestBody::from(body.to_string()))
+                .unwrap()
+        };
+
+        // Only administrators can mint batches.
+        let batch = serde_json::json!({ "count": 3, "max_uses": 2, "expires_in": 3600 });
+        let response = app
+            .clone()
+            .oneshot(request(
+                http::Method::POST,
+                "/admin/invites",
+                member,
+                batch.clone(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = app
+            .clone()
+            .oneshot(request(http::Method::POST, "/admin/invites", admin, batch))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let invites: Vec<Invite> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(invites.len(), 3);
+        assert!(invites
+            .iter()
+            .all(|invite| invite.max_uses == 2 && invite.expire_at.is_some()));
+
+        // Members need a quota.
+        let response = app
+            .clone()
+            .oneshot(request(
+                http::Method::POST,
+                "/users/@me/invites",
+                member,
+                serde_json::json!({}),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = app
+            .clone()
+            .oneshot(request(
+                http::Method::PUT,
+                "/admin/users/member/invite-quota",
+                admin,
+                serde_json::json!({ "quota": 1 }),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = app
+            .clone()
+            .oneshot(request(
+                http::Method::POST,
+                "/users/@me/invites",
+                member,
+                serde_json::json!({}),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let invite: Invite = serde_json::from_slice(&body).unwrap();
+        assert_eq!(invite.created_by.as_deref(), Some("member"));
+
+        let sign_up = |vanity: &str, code: &str| {
+            Request::builder()
+                .method(http::Method::POST)
+                .uri("/create")
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(RequestBody::from(
+                    serde_json::json!({
+                        "vanity": vanity,
+                        "email": format!("{}@gravitalia.com", vanity),
+                        "password": "Tr0mbone-Kayak-42",
+                        "invite": code,
+                    })
+                    .to_string(),
+                ))
+                .unwrap()
+        };
+        let response = app
+            .clone()
+            .oneshot(sign_up("friend", &invite.code))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        // Every user of a multi-use code is listed.
+        for vanity in ["guest", "visitor"] {
+            let response = app
+                .clone()
+                .oneshot(sign_up(vanity, &invites[0].code))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::CREATED);
+        }
+        let response = app
+            .clone()
+            .oneshot(request(
+                http::Method::GET,
+                "/admin/invites",
+                admin,
+                serde_json::json!({}),
+            ))
+            .await
+            .unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let listed: Vec<Invite> = serde_json::from_slice(&body).unwrap();
+        let redeemed = listed
+            .iter()
+            .find(|listed| listed.code == invites[0].code)
+            .unwrap();
+        assert_eq!(redeemed.uses, 2);
+        assert_eq!(
+            redeemed
+                .redemptions
+                .iter()
+                .map(|redemption| redemption.vanity.as_str())
+                .collect::<Vec<_>>(),
+            ["guest", "visitor"]
+        );
+
+        let response = app
+            .oneshot(request(
+                http::Method::GET,
+                "/users/@me/invites",
+                member,
+                serde_json::json!({}),
+            ))
+            .await
+            .unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let invitations: Invitations = serde_json::from_slice(&body).unwrap();
+        assert_eq!(invitations.quota, 0);
+        assert_eq!(invitations.invites[0].used_by.as_deref(), Some("friend"));
+        assert_eq!(invitations.invites[0].redemptions[0].vanity, "friend");
+        assert_eq!(invitations.invites[0].uses, 1);
+    }
+}
@@ -1,50 +1,326 @@
-use argon2::{
-    password_hash::{PasswordHash, PasswordVerifier},
-    Argon2,
+use axum::{
+    extract::State,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response as HttpResponse},
+    Json,
 };
-use axum::{extract::State, Json};
+use rand::distributions::{Alphanumeric, DistString};
+use rand::rngs::OsRng;
 use serde::{Deserialize, Serialize};
 use validator::{Validate, ValidationError, ValidationErrors};
 
-use crate::{database::Database, user::User};
+use std::time::Duration;
+
+use crate::mail::{Email, SharedMailer};
+use crate::{captcha::SharedVerifier, database::Database, user::User};
 
 use super::{ServerError, Valid};
 
+const CHALLENGE_LENGTH: usize = 32;
+/// Codes that can be tried against a challenge before it is dropped.
+const MAX_ATTEMPTS: i32 = 5;
+/// Failed logins after which a captcha is required.
+const CAPTCHA_AFTER_FAILURES: i32 = 3;
+/// Failed logins after which the account is locked.
+const LOCKOUT_AFTER_FAILURES: i32 = 5;
+/// First lockout duration, doubled on each further failure.
+const LOCKOUT_BASE: Duration = Duration::from_secs(60);
+const LOCKOUT_MAX: Duration = Duration::from_secs(24 * 60 * 60);
+
 #[derive(Debug, Serialize, Deserialize, Validate)]
 pub struct Body {
     #[validate(email(message = "Email must be formated."))]
     email: String,
-    #[validate(length(min = 8, message = "Password must contain at least 8 characters."))]
+    #[validate(length(max = 128, message = "Password must contain at most 128 characters."))]
     password: String,
-    _captcha: Option<String>,
+    #[serde(alias = "_captcha")]
+    captcha: Option<String>,
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct Response {
-    user: User,
-    token: String,
+    pub user: User,
+    pub token: String,
+}
+
+/// Answer a completed login, also opening a browser session for `/oauth/authorize`.
+pub(super) fn signed_in(user: User, token: String) -> HttpResponse {
+    (
+        [(header::SET_COOKIE, crate::oauth::session_cookie(&token))],
+        Json(Response { user, token }),
+    )
+        .into_response()
+}
+
+/// Second factor required to complete login.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Challenge {
+    pub challenge: String,
+    pub methods: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct MfaBody {
+    challenge: String,
+    /// TOTP or recovery code.
+    #[validate(length(min = 6, max = 16, message = "Code must contain 6 to 16 characters."))]
+    code: String,
+}
+
+/// Recent failed logins on an account, and how long it stays locked.
+pub(super) async fn failures(
+    db: &Database,
+    email: &str,
+) -> Result<(i32, Option<Duration>), sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT failures, EXTRACT(EPOCH FROM locked_until - NOW())::FLOAT8 AS locked_for
+        FROM "login_failures" WHERE email = $1 AND last_failure_at > NOW() - INTERVAL '1 day'"#,
+        email
+    )
+    .fetch_optional(&db.postgres)
+    .await?;
+
+    Ok(row.map_or((0, None), |row| {
+        let locked_for = row
+            .locked_for
+            .filter(|secs| *secs > 0.0)
+            .map(Duration::from_secs_f64);
+        (row.failures, locked_for)
+    }))
+}
+
+/// Record a failed login, restarting the count after a quiet day.
+///
+/// Past [`LOCKOUT_AFTER_FAILURES`], the account is locked for a doubling duration
+/// and its owner, if any, is told on the first lock.
+pub(super) async fn record_failure(
+    db: &Database,
+    mailer: &SharedMailer,
+    email: &str,
+    user: Option<&User>,
+) -> Result<(), ServerError> {
+    let failures = sqlx::query_scalar!(
+        r#"INSERT INTO "login_failures" (email, failures) values ($1, 1)
+        ON CONFLICT (email) DO UPDATE SET last_failure_at = NOW(),
+        failures = CASE WHEN login_failures.last_failure_at > NOW() - INTERVAL '1 day'
+            THEN login_failures.failures + 1 ELSE 1 END
+        RETURNING failures"#,
+        email
+    )
+    .fetch_one(&db.postgres)
+    .await?;
+
+    if failures < LOCKOUT_AFTER_FAILURES {
+        return Ok(());
+    }
+
+    let exponent = (failures - LOCKOUT_AFTER_FAILURES).min(16);
+    let lockout = (LOCKOUT_BASE * 2u32.pow(exponent as u32)).min(LOCKOUT_MAX);
+    sqlx::query!(
+        r#"UPDATE "login_failures" SET locked_until = NOW() + make_interval(secs => $2)
+        WHERE email = $1"#,
+        email,
+        lockout.as_secs_f64(),
+    )
+    .execute(&db.postgres)
+    .await?;
+
+    if let Some(user) = user {
+        tracing::warn!(vanity = user.vanity, failures, "account locked after failed logins");
+
+        if failures == LOCKOUT_AFTER_FAILURES {
+            let email = Email {
+                to: crate::crypto::email_decryption(user.email.clone()),
+                subject: "Failed login attempts on your account".into(),
+                body: format!(
+                    "Hello {},\n\nSomeone failed to log in to your account {} times, so it has been temporarily locked.\n\nIf it was not you, consider changing your password.",
+                    user.username, failures
+                ),
+            };
+            // Do not make known accounts slower to answer.
+            let mailer = mailer.clone();
+            tokio::spawn(async move {
+                if let Err(err) = mailer.send(email).await {
+                    tracing::error!(error = ?err, "failed to send lockout email");
+                }
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Forget failed logins of an account.
+async fn clear_failures(db: &Database, email: &str) -> Result<(), sqlx::Error> {
+    sqlx::query!(r#"DELETE FROM "login_failures" WHERE email = $1"#, email)
+        .execute(&db.postgres)
+        .await?;
+
+    Ok(())
+}
+
+fn invalid_password() -> ServerError {
+    let error = ValidationError::new("invalid_password").with_message("Password don't match.".into());
+    let mut errors = ValidationErrors::new();
+    errors.add("password", error);
+    errors.into()
 }
 
 pub async fn login(
     State(db): State<Database>,
+    State(captcha): State<SharedVerifier>,
+    State(mailer): State<SharedMailer>,
     Valid(body): Valid<Body>,
-) -> Result<Json<Response>, ServerError> {
+) -> Result<HttpResponse, ServerError> {
     let email = crate::crypto::email_encryption(body.email);
-    let user = User::default().with_email(email).get(&db.postgres).await?;
-    let password = PasswordHash::new(&user.password).unwrap();
-
-    Argon2::default()
-        .verify_password(body.password.as_bytes(), &password)
-        .map_err(|_| {
-            let error = ValidationError::new("invalid_password").with_message("Password don't match.".into());
-            let mut errors = ValidationErrors::new();
-            errors.add("password", error);
-            errors
-        })?;
+    let (failures, locked_for) = failures(&db, &email).await?;
+    if let Some(locked_for) = locked_for {
+        return Err(ServerError::TooManyRequests(locked_for));
+    }
+    if failures >= CAPTCHA_AFTER_FAILURES {
+        super::captcha::require(&captcha, body.captcha.as_deref()).await?;
+    }
+
+    // Unknown users go through the same steps and error as wrong passwords.
+    let user = match User::default().with_email(email.clone()).get(&db.postgres).await {
+        Ok(user) => Some(user),
+        Err(sqlx::Error::RowNotFound) => None,
+        Err(err) => return Err(err.into()),
+    };
+    let verified = match &user {
+        Some(user) => {
+            let (password, hash) = (body.password.clone(), user.password.clone());
+            crate::crypto::blocking(move || {
+                crate::crypto::verify_password(password.as_bytes(), &hash)
+            })
+            .await
+        }
+        None => {
+            let password = body.password.clone();
+            crate::crypto::blocking(move || {
+                crate::crypto::dummy_verify_password(password.as_bytes())
+            })
+            .await;
+            false
+        }
+    };
+    let user = match (user, verified) {
+        (Some(user), true) => user,
+        (user, _) => {
+            record_failure(&db, &mailer, &email, user.as_ref()).await?;
+            return Err(invalid_password());
+        }
+    };
+
+    // Raise cost of hashes made under an older policy.
+    if crate::crypto::needs_rehash(&user.password) {
+        let password = body.password.clone();
+        match crate::crypto::blocking(move || crate::crypto::hash_password(password.as_bytes()))
+            .await
+        {
+            Ok(hash) => {
+                sqlx::query!(
+                    r#"UPDATE "users" SET password = $2 WHERE vanity = $1"#,
+                    user.vanity,
+                    hash,
+                )
+                .execute(&db.postgres)
+                .await?;
+            }
+            Err(err) => tracing::error!(error = ?err, "failed to rehash password"),
+        }
+    }
+
+    if super::mfa::is_enabled(&db.postgres, &user.vanity).await? {
+        let challenge = Alphanumeric.sample_string(&mut OsRng, CHALLENGE_LENGTH);
+
+        sqlx::query!(
+            r#"INSERT INTO "mfa_challenges" (challenge, user_vanity) values ($1, $2)"#,
+            challenge,
+            user.vanity,
+        )
+        .execute(&db.postgres)
+        .await?;
+
+        return Ok((
+            StatusCode::ACCEPTED,
+            Json(Challenge {
+                challenge,
+                methods: vec!["totp".into(), "recovery_code".into()],
+            }),
+        )
+            .into_response());
+    }
 
+    // Failures are only forgotten once login is complete.
+    clear_failures(&db, &email).await?;
+    match user.reactivate(&db.postgres).await {
+        Ok(()) => {}
+        // Past the grace period, the account is as good as unknown.
+        Err(sqlx::Error::RowNotFound) => {
+            record_failure(&db, &mailer, &email, None).await?;
+            return Err(invalid_password());
+        }
+        Err(err) => return Err(err.into()),
+    }
     let token = user.generate_token(&db.postgres).await?;
 
-    Ok(Json(Response { user, token }))
+    Ok(signed_in(user, token))
+}
+
+/// Complete a login challenged for a second factor.
+pub async fn mfa(
+    State(db): State<Database>,
+    State(mailer): State<SharedMailer>,
+    Valid(body): Valid<MfaBody>,
+) -> Result<HttpResponse, ServerError> {
+    let vanity = sqlx::query_scalar!(
+        r#"UPDATE "mfa_challenges" SET attempts = attempts + 1
+        WHERE challenge = $1 AND expire_at > NOW() AND attempts < $2
+        RETURNING user_vanity"#,
+        body.challenge,
+        MAX_ATTEMPTS,
+    )
+    .fetch_optional(&db.postgres)
+    .await?
+    .ok_or(ServerError::Unauthorized)?;
+    let user = User::default().with_vanity(vanity).get(&db.postgres).await?;
+    let vanity = &user.vanity;
+
+    // Wrong codes count towards the account lockout, whatever the challenge.
+    if let (_, Some(locked_for)) = failures(&db, &user.email).await? {
+        return Err(ServerError::TooManyRequests(locked_for));
+    }
+
+    // TOTP codes are digits only, recovery codes are not.
+    let verified = if body.code.bytes().all(|b| b.is_ascii_digit()) {
+        super::mfa::verify(&db.postgres, vanity, &body.code).await?
+    } else {
+        super::mfa::consume_recovery_code(&db.postgres, vanity, &body.code).await?
+    };
+    if !verified {
+        record_failure(&db, &mailer, &user.email, Some(&user)).await?;
+        return Err(super::mfa::invalid_code().into());
+    }
+
+    sqlx::query!(
+        r#"DELETE FROM "mfa_challenges" WHERE challenge = $1"#,
+        body.challenge
+    )
+    .execute(&db.postgres)
+    .await?;
+
+    clear_failures(&db, &user.email).await?;
+    user.reactivate(&db.postgres)
+        .await
+        .map_err(|err| match err {
+            // Past the grace period, the account is as good as unknown.
+            sqlx::Error::RowNotFound => ServerError::Unauthorized,
+            err => err.into(),
+        })?;
+    let token = user.generate_token(&db.postgres).await?;
+
+    Ok(signed_in(user, token))
 }
 
 #[cfg(test)]
@@ -61,32 +337,179 @@ mod tests {
 
     #[sqlx::test]
     async fn test_login_handler(pool: Pool<Postgres>) {
+        sqlx::query!(
+            r#"INSERT INTO "users" (vanity, username, email, password) values ($1, $2, $3, $4)"#,
+            "user",
+            "User",
+            crypto::email_encryption("test@gravitalia.com".into()),
+            crypto::hash_password(b"Password1234").unwrap(),
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
         let state = AppState {
             db: Database { postgres: pool },
             config: status::Configuration::default(),
+            keys: jwt::KeyManager::default(),
+            mailer: std::sync::Arc::new(mail::Stdout),
+            captcha: None,
+        };
+        let app = app(state);
+
+        // Unknown email and wrong password must not be told apart.
+        let mut bodies = Vec::new();
+        for email in ["unknown@gravitalia.com", "test@gravitalia.com"] {
+            let body = Body {
+                email: email.into(),
+                password: "Wrong1234".into(),
+                captcha: None,
+            };
+            let body = serde_json::to_string(&body).unwrap();
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .method(http::Method::POST)
+                        .uri("/login")
+                        .header(http::header::CONTENT_TYPE, "application/json")
+                        .body(RequestBody::from(
+                            body
+                        ))
+                        .unwrap()
+                )
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            bodies.push(response.into_body().collect().await.unwrap().to_bytes());
+        }
+        assert_eq!(bodies[0], bodies[1]);
+    }
+
+    #[sqlx::test]
+    async fn test_lockout(pool: Pool<Postgres>) {
+        sqlx::query!(
+            r#"INSERT INTO "users" (vanity, username, email, password) values ($1, $2, $3, $4)"#,
+            "user",
+            "User",
+            crypto::email_encryption("test@gravitalia.com".into()),
+            crypto::hash_password(b"Password1234").unwrap(),
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let outbox = std::sync::Arc::new(mail::Outbox::default());
+        let state = AppState {
+            db: Database {
+                postgres: pool.clone(),
+            },
+            config: status::Configuration::default(),
+            keys: jwt::KeyManager::default(),
+            mailer: outbox.clone(),
+            captcha: None,
         };
         let app = app(state);
 
-        let body = Body {
-            email: "test@gravitalia.com".into(),
-            password: "Password1234".into(),
-            _captcha: None,
+        let request = |password: &str| {
+            Request::builder()
+                .method(http::Method::POST)
+                .uri("/login")
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(RequestBody::from(
+                    serde_json::json!({ "email": "test@gravitalia.com", "password": password })
+                        .to_string(),
+                ))
+                .unwrap()
         };
-        let body = serde_json::to_string(&body).unwrap();
-        let response = app
+
+        for _ in 0..LOCKOUT_AFTER_FAILURES {
+            let response = app.clone().oneshot(request("Wrong1234")).await.unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+        // Owner is notified in background.
+        while outbox.0.lock().unwrap().is_empty() {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+
+        // Even the right password is refused while locked.
+        let response = app.clone().oneshot(request("Password1234")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry_after: u64 = response.headers()[http::header::RETRY_AFTER]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(retry_after <= LOCKOUT_BASE.as_secs());
+
+        sqlx::query!(r#"UPDATE "login_failures" SET locked_until = NOW()"#)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let response = app.oneshot(request("Password1234")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Browsers also get a session for `/oauth/authorize`.
+        let cookie = response.headers()[http::header::SET_COOKIE].to_str().unwrap();
+        assert!(cookie.starts_with(crate::oauth::SESSION_COOKIE));
+    }
+
+    #[sqlx::test]
+    async fn test_rehash(pool: Pool<Postgres>) {
+        use argon2::password_hash::{PasswordHasher, SaltString};
+        use argon2::Argon2;
+
+        let weak = Argon2::default()
+            .hash_password(b"Password1234", &SaltString::generate(&mut OsRng))
+            .unwrap()
+            .to_string();
+        sqlx::query!(
+            r#"INSERT INTO "users" (vanity, username, email, password) values ($1, $2, $3, $4)"#,
+            "user",
+            "User",
+            crypto::email_encryption("test@gravitalia.com".into()),
+            weak,
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let state = AppState {
+            db: Database {
+                postgres: pool.clone(),
+            },
+            config: status::Configuration::default(),
+            keys: jwt::KeyManager::default(),
+            mailer: std::sync::Arc::new(mail::Stdout),
+            captcha: None,
+        };
+        let response = app(state)
             .oneshot(
                 Request::builder()
                     .method(http::Method::POST)
                     .uri("/login")
                     .header(http::header::CONTENT_TYPE, "application/json")
                     .body(RequestBody::from(
-                        body
+                        serde_json::json!({
+                            "email": "test@gravitalia.com",
+                            "password": "Password1234",
+                        })
+                        .to_string(),
                     ))
-                    .unwrap()
+                    .unwrap(),
             )
             .await
             .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
 
-        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let user = user::User::default()
+            .with_vanity("user".into())
+            .get(&pool)
+            .await
+            .unwrap();
+        assert_ne!(user.password, weak);
+        assert!(!crypto::needs_rehash(&user.password));
+        assert!(crypto::verify_password(b"Password1234", &user.password));
     }
 }
@@ -0,0 +1,111 @@
+//! First-party session termination.
+
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, HeaderName, StatusCode},
+};
+
+use crate::database::Database;
+
+use super::ServerError;
+
+/// Revoke the token used to authenticate the request, and end the browser session.
+pub async fn logout(
+    State(db): State<Database>,
+    headers: HeaderMap,
+) -> Result<(StatusCode, [(HeaderName, String); 1]), ServerError> {
+    let token = crate::oauth::bearer(&headers)
+        .or_else(|| crate::oauth::session(&headers))
+        .ok_or(ServerError::Unauthorized)?;
+
+    sqlx::query!(r#"DELETE FROM "tokens" WHERE token = $1"#, token)
+        .execute(&db.postgres)
+        .await?;
+
+    Ok((
+        StatusCode::NO_CONTENT,
+        [(header::SET_COOKIE, crate::oauth::clear_session_cookie())],
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use axum::{
+        body::Body as RequestBody,
+        http::{self, Request, StatusCode},
+    };
+    use sqlx::{Pool, Postgres};
+    use tower::ServiceExt;
+
+    #[sqlx::test]
+    async fn test_logout_handler(pool: Pool<Postgres>) {
+        sqlx::query!(
+            r#"INSERT INTO "users" (vanity, username, email, password) values ($1, $2, $3, $4)"#,
+            "user",
+            "User",
+            "test@gravitalia.com",
+            "",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let user = user::User::default()
+            .with_vanity("user".into())
+            .get(&pool)
+            .await
+            .unwrap();
+        let token = user.generate_token(&pool).await.unwrap();
+        let session = user.generate_token(&pool).await.unwrap();
+
+        let state = AppState {
+            db: database::Database {
+                postgres: pool.clone(),
+            },
+            config: status::Configuration::default(),
+            keys: jwt::KeyManager::default(),
+            mailer: std::sync::Arc::new(mail::Stdout),
+            captcha: None,
+        };
+        let app = app(state);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/logout")
+                    .header(http::header::AUTHORIZATION, format!("Bearer {}", token))
+                    .body(RequestBody::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(user::User::from_token(&pool, &token).await.is_err());
+
+        // Browser sessions end too.
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/logout")
+                    .header(
+                        http::header::COOKIE,
+                        format!("{}={}", oauth::SESSION_COOKIE, session),
+                    )
+                    .body(RequestBody::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let cookie = response.headers()[http::header::SET_COOKIE]
+            .to_str()
+            .unwrap();
+        assert!(cookie.contains("Max-Age=0"));
+        assert!(user::User::from_token(&pool, &session).await.is_err());
+    }
+}
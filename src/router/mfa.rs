@@ -0,0 +1,559 @@
+//! Multi-factor authentication enrollment.
+//!
+//! Time-based one-time passwords follow RFC 6238 <https://datatracker.ietf.org/doc/html/rfc6238>.
+
+use axum::{extract::State, http::StatusCode, Json};
+use rand::distributions::Uniform;
+use rand::{rngs::OsRng, Rng, RngCore};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use totp_rs::{Algorithm, Secret, TOTP};
+use url::Url;
+use validator::{Validate, ValidationError, ValidationErrors};
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{database::Database, jwt, status::Configuration};
+
+use super::{Authenticated, ServerError, Valid};
+
+/// 160 bits, as recommended by RFC 4226.
+const SECRET_LENGTH: usize = 20;
+const DIGITS: usize = 6;
+const STEP: u64 = 30;
+/// Accepted clock drift, in steps.
+const SKEW: u8 = 1;
+const DEFAULT_ISSUER: &str = "Autha";
+/// Number of recovery codes generated at once.
+const RECOVERY_CODES: usize = 8;
+/// Recovery codes are printed as two groups of this many characters.
+const RECOVERY_CODE_GROUP: usize = 5;
+/// Unambiguous characters used by recovery codes.
+const RECOVERY_CODE_CHARSET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Enrollment {
+    secret: String,
+    uri: String,
+}
+
+/// Printable recovery codes, only shown once.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct RecoveryCodes {
+    recovery_codes: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct Body {
+    #[validate(length(equal = 6, message = "Code must contain 6 digits."))]
+    pub code: String,
+}
+
+/// Bind a sealed TOTP secret to its owner, so it cannot be moved to another account.
+fn secret_aad(vanity: &str) -> String {
+    format!("totp:{vanity}")
+}
+
+fn totp(secret: Vec<u8>, issuer: Option<String>, account: String) -> TOTP {
+    TOTP::new_unchecked(Algorithm::SHA1, DIGITS, SKEW, STEP, secret, issuer, account)
+}
+
+/// Error returned when a code is wrong or already used.
+pub(super) fn invalid_code() -> ValidationErrors {
+    let error = ValidationError::new("invalid_code").with_message("Code is invalid.".into());
+    let mut errors = ValidationErrors::new();
+    errors.add("code", error);
+    errors
+}
+
+/// Check whether `vanity` completed TOTP enrollment.
+pub async fn is_enabled(conn: &Pool<Postgres>, vanity: &str) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"SELECT EXISTS(SELECT 1 FROM "totp" WHERE user_vanity = $1 AND confirmed_at IS NOT NULL) AS "exists!""#,
+        vanity,
+    )
+    .fetch_one(conn)
+    .await
+}
+
+/// Check a TOTP code of `vanity`.
+///
+/// Time steps are only accepted once, so an intercepted code cannot be replayed.
+pub async fn verify(conn: &Pool<Postgres>, vanity: &str, code: &str) -> Result<bool, ServerError> {
+    let Some(row) = sqlx::query!(
+        r#"SELECT secret FROM "totp" WHERE user_vanity = $1"#,
+        vanity
+    )
+    .fetch_optional(conn)
+    .await?
+    else {
+        return Ok(false);
+    };
+
+    let cipher = jwt::cipher().map_err(|err| ServerError::Internal(err.to_string()))?;
+    let secret = jwt::open(cipher.as_ref(), &secret_aad(vanity), &row.secret)
+        .map_err(|err| ServerError::Internal(err.to_string()))?;
+    let Ok(secret) = Secret::Encoded(secret).to_bytes() else {
+        return Ok(false);
+    };
+    let totp = totp(secret, None, String::default());
+    let current = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / STEP;
+
+    let Some(step) = (current - SKEW as u64..=current + SKEW as u64)
+        .find(|step| totp.generate(step * STEP) == code)
+    else {
+        return Ok(false);
+    };
+
+    let updated = sqlx::query!(
+        r#"UPDATE "totp" SET last_step = $2 WHERE user_vanity = $1 AND (last_step IS NULL OR last_step < $2)"#,
+        vanity,
+        step as i64,
+    )
+    .execute(conn)
+    .await?
+    .rows_affected();
+
+    Ok(updated == 1)
+}
+
+/// Remove dashes and spaces users may type along a recovery code.
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+/// Replace recovery codes of `vanity` with new ones.
+pub async fn generate_recovery_codes(
+    conn: &Pool<Postgres>,
+    vanity: &str,
+) -> Result<Vec<String>, ServerError> {
+    let charset = Uniform::from(0..RECOVERY_CODE_CHARSET.len());
+    let codes: Vec<String> = (0..RECOVERY_CODES)
+        .map(|_| {
+            let code: String = (0..RECOVERY_CODE_GROUP * 2)
+                .map(|_| RECOVERY_CODE_CHARSET[OsRng.sample(charset)] as char)
+                .collect();
+            format!(
+                "{}-{}",
+                &code[..RECOVERY_CODE_GROUP],
+                &code[RECOVERY_CODE_GROUP..]
+            )
+        })
+        .collect();
+
+    let mut tx = conn.begin().await?;
+    sqlx::query!(
+        r#"DELETE FROM "recovery_codes" WHERE user_vanity = $1"#,
+        vanity
+    )
+    .execute(&mut *tx)
+    .await?;
+    for code in &codes {
+        sqlx::query!(
+            r#"INSERT INTO "recovery_codes" (user_vanity, code) values ($1, $2)"#,
+            vanity,
+            recovery_code_hash(vanity, code),
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+
+    Ok(codes)
+}
+
+/// Digest of a recovery code, bound to its owner.
+fn recovery_code_hash(vanity: &str, code: &str) -> String {
+    crate::crypto::keyed_hash(&format!("{}:{}", vanity, normalize_recovery_code(code)))
+}
+
+/// Mark a recovery code of `vanity` as used, if it matches an unused one.
+pub async fn consume_recovery_code(
+    conn: &Pool<Postgres>,
+    vanity: &str,
+    code: &str,
+) -> Result<bool, ServerError> {
+    // Concurrent logins must not both consume the same code.
+    let updated = sqlx::query!(
+        r#"UPDATE "recovery_codes" SET used_at = NOW()
+        WHERE user_vanity = $1 AND code = $2 AND used_at IS NULL"#,
+        vanity,
+        recovery_code_hash(vanity, code),
+    )
+    .execute(conn)
+    .await?
+    .rows_affected();
+    if updated == 1 {
+        return Ok(true);
+    }
+
+    // Codes generated before keyed digests are Argon2 hashes.
+    let rows = sqlx::query!(
+        r#"SELECT id, code FROM "recovery_codes"
+        WHERE user_vanity = $1 AND used_at IS NULL AND code LIKE '$argon2%'"#,
+        vanity
+    )
+    .fetch_all(conn)
+    .await?;
+    if rows.is_empty() {
+        return Ok(false);
+    }
+
+    let code = normalize_recovery_code(code);
+    let id = tokio::task::spawn_blocking(move || {
+        rows.into_iter()
+            .find(|row| crate::crypto::verify_password(code.as_bytes(), &row.code))
+            .map(|row| row.id)
+    })
+    .await
+    .map_err(|err| ServerError::Internal(err.to_string()))?;
+    let Some(id) = id else {
+        return Ok(false);
+    };
+
+    let updated = sqlx::query!(
+        r#"UPDATE "recovery_codes" SET used_at = NOW() WHERE id = $1 AND used_at IS NULL"#,
+        id
+    )
+    .execute(conn)
+    .await?
+    .rows_affected();
+
+    Ok(updated == 1)
+}
+
+/// Start TOTP enrollment, replacing any unconfirmed secret.
+pub async fn enroll_totp(
+    State(db): State<Database>,
+    State(config): State<Configuration>,
+    Authenticated(user): Authenticated,
+) -> Result<(StatusCode, Json<Enrollment>), ServerError> {
+    if is_enabled(&db.postgres, &user.vanity).await? {
+        let error =
+            ValidationError::new("already_enabled").with_message("TOTP is already enabled.".into());
+        let mut errors = ValidationErrors::new();
+        errors.add("totp", error);
+        return Err(errors.into());
+    }
+
+    let mut secret = vec![0; SECRET_LENGTH];
+    OsRng.fill_bytes(&mut secret);
+
+    let issuer = Url::parse(&config.url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_owned))
+        .unwrap_or_else(|| DEFAULT_ISSUER.into());
+    let totp = totp(secret, Some(issuer), user.vanity.clone());
+    let enrollment = Enrollment {
+        secret: totp.get_secret_base32(),
+        uri: totp.get_url(),
+    };
+
+    // Secrets are sealed like signing keys, if `SIGNING_KEY_SECRET` is set.
+    let secret = match jwt::cipher().map_err(|err| ServerError::Internal(err.to_string()))? {
+        Some(cipher) => jwt::seal(&cipher, &secret_aad(&user.vanity), &enrollment.secret)
+            .map_err(|err| ServerError::Internal(err.to_string()))?,
+        None => enrollment.secret.clone(),
+    };
+
+    sqlx::query!(
+        r#"INSERT INTO "totp" (user_vanity, secret) values ($1, $2)
+        ON CONFLICT (user_vanity) DO UPDATE SET secret = $2, last_step = NULL, created_at = NOW()"#,
+        user.vanity,
+        secret,
+    )
+    .execute(&db.postgres)
+    .await?;
+
+    Ok((StatusCode::CREATED, Json(enrollment)))
+}
+
+/// Complete TOTP enrollment with a first valid code, returning recovery codes.
+pub async fn confirm_totp(
+    State(db): State<Database>,
+    Authenticated(user): Authenticated,
+    Valid(body): Valid<Body>,
+) -> Result<Json<RecoveryCodes>, ServerError> {
+    if is_enabled(&db.postgres, &user.vanity).await?
+        || !verify(&db.postgres, &user.vanity, &body.code).await?
+    {
+        return Err(invalid_code().into());
+    }
+
+    sqlx::query!(
+        r#"UPDATE "totp" SET confirmed_at = NOW() WHERE user_vanity = $1"#,
+        user.vanity
+    )
+    .execute(&db.postgres)
+    .await?;
+
+    Ok(Json(RecoveryCodes {
+        recovery_codes: generate_recovery_codes(&db.postgres, &user.vanity).await?,
+    }))
+}
+
+/// Invalidate previous recovery codes and generate new ones.
+pub async fn regenerate_recovery_codes(
+    State(db): State<Database>,
+    Authenticated(user): Authenticated,
+) -> Result<Json<RecoveryCodes>, ServerError> {
+    if !is_enabled(&db.postgres, &user.vanity).await? {
+        let error = ValidationError::new("not_enabled")
+            .with_message("Multi-factor authentication is not enabled.".into());
+        let mut errors = ValidationErrors::new();
+        errors.add("totp", error);
+        return Err(errors.into());
+    }
+
+    Ok(Json(RecoveryCodes {
+        recovery_codes: generate_recovery_codes(&db.postgres, &user.vanity).await?,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+    use axum::{
+        body::Body as RequestBody,
+        http::{self, Request},
+    };
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    fn code(secret: &str, offset: i64) -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let totp = super::totp(
+            Secret::Encoded(secret.into()).to_bytes().unwrap(),
+            None,
+            String::default(),
+        );
+        totp.generate((now as i64 + offset) as u64)
+    }
+
+    #[sqlx::test]
+    async fn test_totp_login(pool: Pool<Postgres>) {
+        let password = crate::crypto::hash_password(b"Password1234").unwrap();
+        sqlx::query!(
+            r#"INSERT INTO "users" (vanity, username, email, password) values ($1, $2, $3, $4)"#,
+            "user",
+            "User",
+            crate::crypto::email_encryption("test@gravitalia.com".into()),
+            password,
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let token = user::User::default()
+            .with_vanity("user".into())
+            .get(&pool)
+            .await
+            .unwrap()
+            .generate_token(&pool)
+            .await
+            .unwrap();
+
+        let state = AppState {
+            db: database::Database { postgres: pool },
+            config: status::Configuration::default(),
+            keys: jwt::KeyManager::default(),
+            mailer: std::sync::Arc::new(mail::Stdout),
+            captcha: None,
+        };
+        let app = app(state);
+
+        let request = |uri: &str, token: Option<&str>, body: serde_json::Value| {
+            let mut request = Request::builder()
+                .method(http::Method::POST)
+                .uri(uri)
+                .header(http::header::CONTENT_TYPE, "application/json");
+            if let Some(token) = token {
+                request = request.header(http::header::AUTHORIZATION, format!("Bearer {}", token));
+            }
+            request.body(RequestBody::from(body.to_string())).unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(request(
+                "/users/@me/mfa/totp",
+                Some(&token),
+                serde_json::json!({}),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let enrollment: Enrollment = serde_json::from_slice(&body).unwrap();
+        assert!(enrollment.uri.starts_with("otpauth://totp/"));
+
+        let confirm = code(&enrollment.secret, 0);
+        let response = app
+            .clone()
+            .oneshot(request(
+                "/users/@me/mfa/totp/confirm",
+                Some(&token),
+                serde_json::json!({ "code": confirm }),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let recovery: RecoveryCodes = serde_json::from_slice(&body).unwrap();
+        assert_eq!(recovery.recovery_codes.len(), RECOVERY_CODES);
+
+        // Password alone is no longer enough.
+        let response = app
+            .clone()
+            .oneshot(request(
+                "/login",
+                None,
+                serde_json::json!({ "email": "test@gravitalia.com", "password": "Password1234" }),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let challenge: router::login::Challenge = serde_json::from_slice(&body).unwrap();
+
+        // Code used for confirmation cannot be replayed.
+        let response = app
+            .clone()
+            .oneshot(request(
+                "/login/mfa",
+                None,
+                serde_json::json!({ "challenge": challenge.challenge, "code": confirm }),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = app
+            .clone()
+            .oneshot(request(
+                "/login/mfa",
+                None,
+                serde_json::json!({
+                    "challenge": challenge.challenge,
+                    "code": code(&enrollment.secret, STEP as i64),
+                }),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Recovery codes replace TOTP, only once.
+        for status in [StatusCode::OK, StatusCode::BAD_REQUEST] {
+            let response = app
+                .clone()
+                .oneshot(request(
+                    "/login",
+                    None,
+                    serde_json::json!({ "email": "test@gravitalia.com", "password": "Password1234" }),
+                ))
+                .await
+                .unwrap();
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            let challenge: router::login::Challenge = serde_json::from_slice(&body).unwrap();
+
+            let response = app
+                .clone()
+                .oneshot(request(
+                    "/login/mfa",
+                    None,
+                    serde_json::json!({
+                        "challenge": challenge.challenge,
+                        "code": recovery.recovery_codes[0],
+                    }),
+                ))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), status);
+        }
+    }
+
+    #[sqlx::test]
+    async fn test_mfa_lockout(pool: Pool<Postgres>) {
+        let password = crate::crypto::hash_password(b"Password1234").unwrap();
+        sqlx::query!(
+            r#"INSERT INTO "users" (vanity, username, email, password) values ($1, $2, $3, $4)"#,
+            "user",
+            "User",
+            crate::crypto::email_encryption("test@gravitalia.com".into()),
+            password,
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query!(
+            r#"INSERT INTO "totp" (user_vanity, secret, confirmed_at) values ($1, $2, NOW())"#,
+            "user",
+            "JBSWY3DPEHPK3PXPJBSWY3DPEHPK3PXP",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let state = AppState {
+            db: database::Database { postgres: pool },
+            config: status::Configuration::default(),
+            keys: jwt::KeyManager::default(),
+            mailer: std::sync::Arc::new(mail::Stdout),
+            captcha: None,
+        };
+        let app = app(state);
+
+        let request = |uri: &str, body: serde_json::Value| {
+            Request::builder()
+                .method(http::Method::POST)
+                .uri(uri)
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(RequestBody::from(body.to_string()))
+                .unwrap()
+        };
+
+        // The sixth wrong code is refused, even on a new challenge.
+        for statuses in [
+            [StatusCode::BAD_REQUEST; 3],
+            [
+                StatusCode::BAD_REQUEST,
+                StatusCode::BAD_REQUEST,
+                StatusCode::TOO_MANY_REQUESTS,
+            ],
+        ] {
+            let response = app
+                .clone()
+                .oneshot(request(
+                    "/login",
+                    serde_json::json!({ "email": "test@gravitalia.com", "password": "Password1234" }),
+                ))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::ACCEPTED);
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            let challenge: router::login::Challenge = serde_json::from_slice(&body).unwrap();
+
+            for status in statuses {
+                let response = app
+                    .clone()
+                    .oneshot(request(
+                        "/login/mfa",
+                        serde_json::json!({ "challenge": challenge.challenge, "code": "000000" }),
+                    ))
+                    .await
+                    .unwrap();
+                assert_eq!(response.status(), status);
+            }
+        }
+    }
+}
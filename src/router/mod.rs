@@ -1,12 +1,20 @@
 //! Route handler module with HTTP routes and validation.
 
+pub mod captcha;
 pub mod create;
+pub mod email;
+pub mod invites;
 pub mod login;
+pub mod logout;
+pub mod mfa;
+pub mod password;
 pub mod status;
+pub mod users;
+pub mod webauthn;
 
 use axum::{
-    extract::{rejection::JsonRejection, FromRequest, Request},
-    http::{header, StatusCode},
+    extract::{rejection::JsonRejection, FromRef, FromRequest, FromRequestParts, Request},
+    http::{header, request::Parts, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
@@ -15,6 +23,11 @@ use sqlx::{postgres::PgDatabaseError, Error as SQLxError};
 use thiserror::Error;
 use validator::{Validate, ValidationErrors};
 
+use crate::{
+    database::Database,
+    user::{User, FLAG_ADMIN},
+};
+
 /// A wrapper struct for validating form data.
 #[derive(Debug, Clone, Copy, Default)]
 pub struct Valid<T>(pub T);
@@ -34,6 +47,50 @@ where
     }
 }
 
+/// User authenticated with an `Authorization: Bearer` token.
+#[derive(Debug)]
+pub struct Authenticated(pub User);
+
+impl<S> FromRequestParts<S> for Authenticated
+where
+    Database: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = ServerError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let token = crate::oauth::bearer(&parts.headers).ok_or(ServerError::Unauthorized)?;
+
+        match User::from_token(&Database::from_ref(state).postgres, token).await {
+            Ok(user) => Ok(Authenticated(user)),
+            Err(SQLxError::RowNotFound) => Err(ServerError::Unauthorized),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// Authenticated user holding the administrator flag.
+#[derive(Debug)]
+pub struct Admin(pub User);
+
+impl<S> FromRequestParts<S> for Admin
+where
+    Database: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = ServerError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Authenticated(user) = Authenticated::from_request_parts(parts, state).await?;
+
+        if user.flags & FLAG_ADMIN == 0 {
+            return Err(ServerError::Forbidden);
+        }
+
+        Ok(Admin(user))
+    }
+}
+
 /// Enum representing server-side errors.
 #[derive(Debug, Error)]
 pub enum ServerError {
@@ -46,6 +103,21 @@ pub enum ServerError {
     #[error("SQL request failed: {0}")]
     Sql(#[from] SQLxError),
 
+    #[error("Unauthorized")]
+    Unauthorized,
+
+    #[error("Forbidden")]
+    Forbidden,
+
+    #[error("Not found")]
+    NotFound,
+
+    #[error("Payload too large")]
+    PayloadTooLarge,
+
+    #[error("Too many requests, retry after {0:?}")]
+    TooManyRequests(std::time::Duration),
+
     #[error("Internal server error")]
     Internal(String),
 }
@@ -156,6 +228,44 @@ impl IntoResponse for ServerError {
                 .status(StatusCode::BAD_REQUEST)
                 .into_response()
                 .unwrap_or_else(|_| internal_server_error()),
+            ServerError::Unauthorized => ResponseError::default()
+                .title("Unauthorized.")
+                .details("A valid bearer token is required.")
+                .status(StatusCode::UNAUTHORIZED)
+                .into_response()
+                .unwrap_or_else(|_| internal_server_error()),
+            ServerError::Forbidden => ResponseError::default()
+                .title("Forbidden.")
+                .details("You are not allowed to perform this action.")
+                .status(StatusCode::FORBIDDEN)
+                .into_response()
+                .unwrap_or_else(|_| internal_server_error()),
+            ServerError::NotFound => ResponseError::default()
+                .title("Not found.")
+                .details("The requested resource does not exist.")
+                .status(StatusCode::NOT_FOUND)
+                .into_response()
+                .unwrap_or_else(|_| internal_server_error()),
+            ServerError::PayloadTooLarge => ResponseError::default()
+                .title("Payload too large.")
+                .details("The request body exceeds the allowed size.")
+                .status(StatusCode::PAYLOAD_TOO_LARGE)
+                .into_response()
+                .unwrap_or_else(|_| internal_server_error()),
+            ServerError::TooManyRequests(retry_after) => {
+                let mut response = ResponseError::default()
+                    .title("Too many requests.")
+                    .details("Rate limit exceeded, please retry later.")
+                    .status(StatusCode::TOO_MANY_REQUESTS)
+                    .into_response()
+                    .unwrap_or_else(|_| internal_server_error());
+                // Round up, retrying early would fail again.
+                let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+                response
+                    .headers_mut()
+                    .insert(header::RETRY_AFTER, seconds.into());
+                response
+            }
             ServerError::Internal(_err) => internal_server_error(),
         }
     }
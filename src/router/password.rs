@@ -0,0 +1,258 @@
+//! Password reset by emailed single-use token.
+
+use axum::{extract::State, http::StatusCode};
+use rand::distributions::{Alphanumeric, DistString};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use validator::{Validate, ValidationError, ValidationErrors};
+
+use crate::mail::{Email, SharedMailer};
+use crate::{database::Database, user::User};
+
+use super::{ServerError, Valid};
+
+const TOKEN_LENGTH: usize = 48;
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct ForgotBody {
+    #[validate(email(message = "Email must be formated."))]
+    email: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct ResetBody {
+    #[validate(length(equal = 48, message = "Token must contain 48 characters."))]
+    token: String,
+    #[validate(custom(function = "crate::password::validate"))]
+    password: String,
+}
+
+/// Email a reset token to the owner of `email`, if any.
+async fn send_reset(
+    db: &Database,
+    mailer: &SharedMailer,
+    email: String,
+) -> Result<(), ServerError> {
+    let user = match User::default()
+        .with_email(crate::crypto::email_encryption(email.clone()))
+        .get(&db.postgres)
+        .await
+    {
+        Ok(user) => user,
+        Err(sqlx::Error::RowNotFound) => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    let token = Alphanumeric.sample_string(&mut OsRng, TOKEN_LENGTH);
+
+    sqlx::query!(
+        r#"INSERT INTO "password_resets" (token, user_vanity) values ($1, $2)"#,
+        crate::crypto::hash_token(&token),
+        user.vanity,
+    )
+    .execute(&db.postgres)
+    .await?;
+
+    mailer
+        .send(Email {
+            to: email,
+            subject: "Reset your password".into(),
+            body: format!(
+                "Hello {},\n\nUse this code to choose a new password: {}\n\nIt expires in 30 minutes. If you did not ask for it, ignore this email.",
+                user.username, token
+            ),
+        })
+        .await
+        .map_err(|err| ServerError::Internal(err.to_string()))
+}
+
+/// Request a password reset.
+///
+/// Always accepted, so it cannot be used to find out whether an email is registered.
+pub async fn forgot(
+    State(db): State<Database>,
+    State(mailer): State<SharedMailer>,
+    Valid(body): Valid<ForgotBody>,
+) -> StatusCode {
+    // Work in background to keep response time independent of the email.
+    tokio::spawn(async move {
+        if let Err(err) = send_reset(&db, &mailer, body.email).await {
+            tracing::error!(error = ?err, "failed to send password reset email");
+        }
+    });
+
+    StatusCode::ACCEPTED
+}
+
+/// Choose a new password using a reset token, signing out every session.
+pub async fn reset(
+    State(db): State<Database>,
+    Valid(body): Valid<ResetBody>,
+) -> Result<StatusCode, ServerError> {
+    crate::password::check_breach("password", &body.password).await?;
+    let password = body.password.clone();
+    let password =
+        crate::crypto::blocking(move || crate::crypto::hash_password(password.as_bytes()))
+            .await
+            .map_err(|err| ServerError::Internal(err.to_string()))?;
+
+    let mut tx = db.postgres.begin().await?;
+
+    let Some(vanity) = sqlx::query_scalar!(
+        r#"DELETE FROM "password_resets" WHERE token = $1 AND expire_at > NOW() RETURNING user_vanity"#,
+        crate::crypto::hash_token(&body.token),
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    else {
+        let error =
+            ValidationError::new("invalid_token").with_message("Token is invalid or expired.".into());
+        let mut errors = ValidationErrors::new();
+        errors.add("token", error);
+        return Err(errors.into());
+    };
+
+    let email = sqlx::query_scalar!(
+        r#"UPDATE "users" SET password = $2 WHERE vanity = $1 RETURNING email"#,
+        vanity,
+        password,
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    // The owner proved who they are, lift any lockout.
+    sqlx::query!(r#"DELETE FROM "login_failures" WHERE email = $1"#, email)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!(
+        r#"DELETE FROM "password_resets" WHERE user_vanity = $1"#,
+        vanity
+    )
+    .execute(&mut *tx)
+    .await?;
+    User::default()
+        .with_vanity(vanity)
+        .revoke_tokens(&mut tx, None)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mail::Outbox;
+    use crate::*;
+    use axum::{
+        body::Body as RequestBody,
+        http::{self, Request, StatusCode},
+    };
+    use sqlx::{Pool, Postgres};
+    use tower::ServiceExt;
+
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[sqlx::test]
+    async fn test_reset_handler(pool: Pool<Postgres>) {
+        sqlx::query!(
+            r#"INSERT INTO "users" (vanity, username, email, password) values ($1, $2, $3, $4)"#,
+            "user",
+            "User",
+            crypto::email_encryption("test@gravitalia.com".into()),
+            crypto::hash_password(b"Password1234").unwrap(),
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let token = user::User::default()
+            .with_vanity("user".into())
+            .get(&pool)
+            .await
+            .unwrap()
+            .generate_token(&pool)
+            .await
+            .unwrap();
+
+        let outbox = Arc::new(Outbox::default());
+        let state = AppState {
+            db: database::Database {
+                postgres: pool.clone(),
+            },
+            config: status::Configuration::default(),
+            keys: jwt::KeyManager::default(),
+            mailer: outbox.clone(),
+            captcha: None,
+        };
+        let app = app(state);
+
+        let request = |uri: &str, body: serde_json::Value| {
+            Request::builder()
+                .method(http::Method::POST)
+                .uri(uri)
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(RequestBody::from(body.to_string()))
+                .unwrap()
+        };
+
+        // Unknown emails get the same answer.
+        for email in ["unknown@gravitalia.com", "test@gravitalia.com"] {
+            let response = app
+                .clone()
+                .oneshot(request(
+                    "/password/forgot",
+                    serde_json::json!({ "email": email }),
+                ))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::ACCEPTED);
+        }
+
+        let email = loop {
+            if let Some(email) = outbox.0.lock().unwrap().pop() {
+                break email;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        };
+        assert_eq!(email.to, "test@gravitalia.com");
+        let code = email
+            .body
+            .split_whitespace()
+            .find(|word| word.len() == 48)
+            .unwrap()
+            .to_owned();
+
+        sqlx::query!(
+            r#"INSERT INTO "login_failures" (email, failures, locked_until)
+            values ($1, 5, NOW() + INTERVAL '1 hour')"#,
+            crypto::email_encryption("test@gravitalia.com".into()),
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let body = serde_json::json!({ "token": code, "password": "Tr0mbone-Kayak-42" });
+        let response = app
+            .clone()
+            .oneshot(request("/password/reset", body.clone()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        // Sessions are revoked, lockout is lifted and token is single-use.
+        assert!(user::User::from_token(&pool, &token).await.is_err());
+        let failures = sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM "login_failures""#)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(failures, 0);
+        let response = app.oneshot(request("/password/reset", body)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let user = user::User::default()
+            .with_vanity("user".into())
+            .get(&pool)
+            .await
+            .unwrap();
+        assert!(crypto::verify_password(b"Tr0mbone-Kayak-42", &user.password));
+    }
+}
@@ -25,6 +25,9 @@ mod tests {
         let state = AppState {
             db: database::Database { postgres: pool },
             config: status::Configuration::default(),
+            keys: jwt::KeyManager::default(),
+            mailer: std::sync::Arc::new(mail::Stdout),
+            captcha: None,
         };
         let app = app(state);
 
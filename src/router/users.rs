@@ -0,0 +1,701 @@
+//! User accounts.
+
+use axum::extract::{Path, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use url::Url;
+use validator::{Validate, ValidationError, ValidationErrors};
+
+use crate::{database::Database, mail::SharedMailer, status::Configuration, user::User};
+
+use super::{Authenticated, ServerError, Valid};
+
+const ACTIVITY_JSON: &str = "application/activity+json";
+const LD_JSON: &str = "application/ld+json";
+
+/// Public key of an actor (<https://w3id.org/security/v1>).
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PublicKey {
+    id: String,
+    owner: String,
+    public_key_pem: String,
+}
+
+/// ActivityStreams `Person` actor.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Person {
+    #[serde(rename = "@context")]
+    context: Vec<String>,
+    id: String,
+    r#type: String,
+    preferred_username: String,
+    name: String,
+    /// Required of every actor by ActivityPub.
+    inbox: String,
+    outbox: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    icon: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    public_key: Option<PublicKey>,
+}
+
+/// ActivityStreams `OrderedCollection`, such as an actor inbox or outbox.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OrderedCollection {
+    #[serde(rename = "@context")]
+    context: String,
+    id: String,
+    r#type: String,
+    total_items: usize,
+    ordered_items: Vec<serde_json::Value>,
+}
+
+/// Partial update of the authenticated user.
+#[derive(Debug, Default, Serialize, Deserialize, Validate)]
+pub struct Body {
+    #[validate(length(
+        min = 2,
+        max = 30,
+        message = "Username must contain 2 to 30 characters."
+    ))]
+    username: Option<String>,
+    /// Empty to remove the avatar.
+    #[validate(length(max = 2048), custom(function = "validate_avatar"))]
+    avatar: Option<String>,
+    #[validate(email(message = "Email must be formated."))]
+    email: Option<String>,
+    #[validate(custom(function = "crate::password::validate"))]
+    new_password: Option<String>,
+    /// Current password, required to change email or password.
+    password: Option<String>,
+}
+
+/// Avatars are shown by other servers and clients, so only HTTPS URLs are accepted.
+fn validate_avatar(avatar: &str) -> Result<(), ValidationError> {
+    if avatar.is_empty() || Url::parse(avatar).is_ok_and(|url| url.scheme() == "https") {
+        return Ok(());
+    }
+
+    Err(ValidationError::new("url").with_message("Avatar must be an HTTPS URL.".into()))
+}
+
+/// Get the authenticated user.
+pub async fn me(Authenticated(user): Authenticated) -> Json<User> {
+    Json(user)
+}
+
+/// Update profile of the authenticated user.
+///
+/// A new password ends every other session.
+pub async fn update_me(
+    State(db): State<Database>,
+    State(mailer): State<SharedMailer>,
+    headers: HeaderMap,
+    Authenticated(user): Authenticated,
+    Valid(body): Valid<Body>,
+) -> Result<Json<User>, ServerError> {
+    let sensitive = body.email.is_some() || body.new_password.is_some();
+    if sensitive {
+        // Wrong passwords count towards the login lockout.
+        if let (_, Some(locked_for)) = super::login::failures(&db, &user.email).await? {
+            return Err(ServerError::TooManyRequests(locked_for));
+        }
+
+        let verified = match body.password.clone() {
+            Some(password) => {
+                let hash = user.password.clone();
+                crate::crypto::blocking(move || {
+                    crate::crypto::verify_password(password.as_bytes(), &hash)
+                })
+                .await
+            }
+            None => false,
+        };
+        if !verified {
+            if body.password.is_some() {
+                super::login::record_failure(&db, &mailer, &user.email, Some(&user)).await?;
+            }
+
+            let error = ValidationError::new("invalid_password")
+                .with_message("Current password is required to change email or password.".into());
+            let mut errors = ValidationErrors::new();
+            errors.add("password", error);
+            return Err(errors.into());
+        }
+    }
+    if let Some(password) = &body.new_password {
+        crate::password::check_breach("new_password", password).await?;
+    }
+
+    let email = body.email.map(crate::crypto::email_encryption);
+    let password = match body.new_password {
+        Some(password) => Some(
+            crate::crypto::blocking(move || crate::crypto::hash_password(password.as_bytes()))
+                .await
+                .map_err(|err| ServerError::Internal(err.to_string()))?,
+        ),
+        None => None,
+    };
+
+    let email_changed = email.as_ref().is_some_and(|email| *email != user.email);
+
+    let mut tx = db.postgres.begin().await?;
+    // `updated_at` is set by trigger.
+    let user = sqlx::query_as!(
+        User,
+        r#"UPDATE "users" SET
+            username = COALESCE($2, username),
+            avatar = CASE WHEN $3::TEXT IS NULL THEN avatar ELSE NULLIF($3, '') END,
+            email = COALESCE($4, email),
+            password = COALESCE($5, password),
+            flags = CASE WHEN $6 THEN flags & ~$7::INT ELSE flags END
+        WHERE vanity = $1
+        RETURNING vanity, username, email, avatar, flags, password"#,
+        user.vanity,
+        body.username,
+        body.avatar,
+        email,
+        password,
+        email_changed,
+        crate::user::FLAG_VERIFIED_EMAIL,
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    if password.is_some() {
+        user.revoke_tokens(&mut tx, crate::oauth::bearer(&headers))
+            .await?;
+    }
+    tx.commit().await?;
+
+    // Change is already saved, verification can be requested again.
+    if email_changed {
+        if let Err(err) = super::email::send_verification(&db, mailer.as_ref(), &user).await {
+            tracing::error!(error = ?err, "failed to send verification email");
+        }
+    }
+
+    Ok(Json(user))
+}
+
+/// Delete the authenticated user.
+///
+/// Account stays recoverable by logging in during [`crate::user::DELETION_GRACE_DAYS`].
+pub async fn delete_me(
+    State(db): State<Database>,
+    Authenticated(user): Authenticated,
+) -> Result<StatusCode, ServerError> {
+    let mut tx = db.postgres.begin().await?;
+
+    sqlx::query!(
+        r#"UPDATE "users" SET deleted_at = CURRENT_DATE WHERE vanity = $1"#,
+        user.vanity
+    )
+    .execute(&mut *tx)
+    .await?;
+    user.revoke_tokens(&mut tx, None).await?;
+
+    tx.commit().await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Get a user who is neither suspended nor deleted.
+async fn active_user(db: &Database, vanity: &str) -> Result<User, ServerError> {
+    sqlx::query_as!(
+        User,
+        r#"SELECT vanity, username, email, avatar, flags, password FROM users
+        WHERE vanity = $1 AND suspended_at IS NULL AND deleted_at IS NULL"#,
+        vanity.to_lowercase(),
+    )
+    .fetch_optional(&db.postgres)
+    .await?
+    .ok_or(ServerError::NotFound)
+}
+
+/// ActivityPub `id` of the actor of `vanity`.
+fn actor_id(config: &Configuration, vanity: &str) -> Result<String, ServerError> {
+    let mut id =
+        url::Url::parse(&config.url).map_err(|err| ServerError::Internal(err.to_string()))?;
+    id.set_path(&format!("/users/{}", vanity));
+
+    Ok(id.to_string())
+}
+
+/// Empty collection `name` of an actor.
+async fn collection(
+    db: &Database,
+    config: &Configuration,
+    vanity: &str,
+    name: &str,
+) -> Result<Response, ServerError> {
+    let user = active_user(db, vanity).await?;
+
+    let collection = OrderedCollection {
+        context: "https://www.w3.org/ns/activitystreams".into(),
+        id: format!("{}/{}", actor_id(config, &user.vanity)?, name),
+        r#type: "OrderedCollection".into(),
+        total_items: 0,
+        ordered_items: Vec::new(),
+    };
+
+    Ok(([(header::CONTENT_TYPE, ACTIVITY_JSON)], Json(collection)).into_response())
+}
+
+/// Get the inbox of an actor, always empty as accounts do not federate activities.
+///
+/// Deliveries are refused with `405 Method Not Allowed`.
+pub async fn inbox(
+    State(db): State<Database>,
+    State(config): State<Configuration>,
+    Path(vanity): Path<String>,
+) -> Result<Response, ServerError> {
+    collection(&db, &config, &vanity, "inbox").await
+}
+
+/// Get the outbox of an actor, always empty as accounts publish no activities.
+pub async fn outbox(
+    State(db): State<Database>,
+    State(config): State<Configuration>,
+    Path(vanity): Path<String>,
+) -> Result<Response, ServerError> {
+    collection(&db, &config, &vanity, "outbox").await
+}
+
+/// Get the public profile of a user.
+///
+/// ActivityPub clients asking for `application/activity+json` get a `Person` actor.
+pub async fn get(
+    State(db): State<Database>,
+    State(config): State<Configuration>,
+    Path(vanity): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, ServerError> {
+    let user = active_user(&db, &vanity).await?;
+
+    let activity = headers
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|accept| accept.contains(ACTIVITY_JSON) || accept.contains(LD_JSON));
+    if !activity {
+        return Ok(([(header::VARY, "Accept")], Json(user)).into_response());
+    }
+
+    let id = actor_id(&config, &user.vanity)?;
+
+    let public_key = sqlx::query_scalar!(
+        r#"SELECT k.key FROM users u JOIN keys k ON k.id = u.public_keys WHERE u.vanity = $1"#,
+        user.vanity,
+    )
+    .fetch_optional(&db.postgres)
+    .await?
+    .map(|key| PublicKey {
+        id: format!("{}#main-key", id),
+        owner: id.clone(),
+        public_key_pem: key,
+    });
+
+    let person = Person {
+        context: vec![
+            "https://www.w3.org/ns/activitystreams".into(),
+            "https://w3id.org/security/v1".into(),
+        ],
+        inbox: format!("{}/inbox", id),
+        outbox: format!("{}/outbox", id),
+        id,
+        r#type: "Person".into(),
+        preferred_username: user.vanity,
+        name: user.username,
+        icon: user.avatar,
+        public_key,
+    };
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, ACTIVITY_JSON),
+            (header::VARY, "Accept"),
+        ],
+        Json(person),
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use axum::{
+        body::Body as RequestBody,
+        http::{self, Request, StatusCode},
+    };
+    use http_body_util::BodyExt;
+    use sqlx::{Pool, Postgres};
+    use tower::ServiceExt;
+
+    #[sqlx::test]
+    async fn test_me_handler(pool: Pool<Postgres>) {
+        sqlx::query!(
+            r#"INSERT INTO "users" (vanity, username, email, password) values ($1, $2, $3, $4)"#,
+            "user",
+            "User",
+            "test@gravitalia.com",
+            "",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let token = user::User::default()
+            .with_vanity("user".into())
+            .get(&pool)
+            .await
+            .unwrap()
+            .generate_token(&pool)
+            .await
+            .unwrap();
+
+        let state = AppState {
+            db: database::Database {
+                postgres: pool.clone(),
+            },
+            config: status::Configuration::default(),
+            keys: jwt::KeyManager::default(),
+            mailer: std::sync::Arc::new(mail::Stdout),
+            captcha: None,
+        };
+        let app = app(state);
+
+        let request = || {
+            Request::builder()
+                .method(http::Method::GET)
+                .uri("/users/@me")
+                .header(http::header::AUTHORIZATION, format!("Bearer {}", token))
+                .body(RequestBody::empty())
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let user: user::User = serde_json::from_slice(&body).unwrap();
+        assert_eq!(user.vanity, "user");
+
+        // Suspended users cannot use their tokens.
+        sqlx::query!(
+            r#"UPDATE "users" SET suspended_at = NOW() WHERE vanity = $1"#,
+            "user"
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let response = app.oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[sqlx::test]
+    async fn test_update_me_handler(pool: Pool<Postgres>) {
+        sqlx::query!(
+            r#"INSERT INTO "users" (vanity, username, email, password) values ($1, $2, $3, $4)"#,
+            "user",
+            "User",
+            crypto::email_encryption("test@gravitalia.com".into()),
+            crypto::hash_password(b"Password1234").unwrap(),
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let token = user::User::default()
+            .with_vanity("user".into())
+            .get(&pool)
+            .await
+            .unwrap()
+            .generate_token(&pool)
+            .await
+            .unwrap();
+
+        let state = AppState {
+            db: database::Database {
+                postgres: pool.clone(),
+            },
+            config: status::Configuration::default(),
+            keys: jwt::KeyManager::default(),
+            mailer: std::sync::Arc::new(mail::Stdout),
+            captcha: None,
+        };
+        let app = app(state);
+
+        let request = |body: serde_json::Value| {
+            Request::builder()
+                .method(http::Method::PATCH)
+                .uri("/users/@me")
+                .header(http::header::AUTHORIZATION, format!("Bearer {}", token))
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(RequestBody::from(body.to_string()))
+                .unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(request(serde_json::json!({ "username": "Gravitalian" })))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let user: user::User = serde_json::from_slice(&body).unwrap();
+        assert_eq!(user.username, "Gravitalian");
+
+        let updated = sqlx::query_scalar!(
+            r#"SELECT updated_at IS NOT NULL AS "updated!" FROM "users" WHERE vanity = $1"#,
+            "user"
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert!(updated);
+
+        // Sensitive changes need the current password.
+        let response = app
+            .clone()
+            .oneshot(request(
+                serde_json::json!({ "email": "new@gravitalia.com", "password": "Wrong1234" }),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let failures = sqlx::query_scalar!(
+            r#"SELECT failures FROM "login_failures" WHERE email = $1"#,
+            crypto::email_encryption("test@gravitalia.com".into()),
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(failures, 1);
+
+        let response = app
+            .clone()
+            .oneshot(request(
+                serde_json::json!({ "avatar": "http://gravitalia.com/avatar.png" }),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = app
+            .clone()
+            .oneshot(request(
+                serde_json::json!({ "email": "new@gravitalia.com", "password": "Password1234" }),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let user = user::User::default()
+            .with_email(crypto::email_encryption("new@gravitalia.com".into()))
+            .get(&pool)
+            .await
+            .unwrap();
+
+        // A new password ends other sessions only.
+        let other = user.generate_token(&pool).await.unwrap();
+        let response = app
+            .oneshot(request(serde_json::json!({
+                "new_password": "Tr0mbone-Kayak-42",
+                "password": "Password1234",
+            })))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(user::User::from_token(&pool, &token).await.is_ok());
+        assert!(user::User::from_token(&pool, &other).await.is_err());
+    }
+
+    #[sqlx::test]
+    async fn test_delete_me_handler(pool: Pool<Postgres>) {
+        sqlx::query!(
+            r#"INSERT INTO "users" (vanity, username, email, password) values ($1, $2, $3, $4)"#,
+            "user",
+            "User",
+            crypto::email_encryption("test@gravitalia.com".into()),
+            crypto::hash_password(b"Password1234").unwrap(),
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let token = user::User::default()
+            .with_vanity("user".into())
+            .get(&pool)
+            .await
+            .unwrap()
+            .generate_token(&pool)
+            .await
+            .unwrap();
+
+        let state = AppState {
+            db: database::Database {
+                postgres: pool.clone(),
+            },
+            config: status::Configuration::default(),
+            keys: jwt::KeyManager::default(),
+            mailer: std::sync::Arc::new(mail::Stdout),
+            captcha: None,
+        };
+        let app = app(state);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::DELETE)
+                    .uri("/users/@me")
+                    .header(http::header::AUTHORIZATION, format!("Bearer {}", token))
+                    .body(RequestBody::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(user::User::from_token(&pool, &token).await.is_err());
+
+        // Logging in during grace period reactivates the account.
+        let login = |email: &str| {
+            Request::builder()
+                .method(http::Method::POST)
+                .uri("/login")
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(RequestBody::from(
+                    serde_json::json!({ "email": email, "password": "Password1234" }).to_string(),
+                ))
+                .unwrap()
+        };
+        let response = app
+            .clone()
+            .oneshot(login("test@gravitalia.com"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Expired accounts are purged.
+        sqlx::query!(
+            r#"UPDATE "users" SET deleted_at = CURRENT_DATE - 31 WHERE vanity = $1"#,
+            "user"
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let response = app
+            .clone()
+            .oneshot(login("test@gravitalia.com"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let expired = response.into_body().collect().await.unwrap().to_bytes();
+        let response = app.oneshot(login("unknown@gravitalia.com")).await.unwrap();
+        let unknown = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(expired, unknown);
+        assert_eq!(user::purge(&pool).await.unwrap(), 1);
+    }
+
+    #[sqlx::test]
+    async fn test_get_handler(pool: Pool<Postgres>) {
+        let key = sqlx::query_scalar!(
+            r#"INSERT INTO "keys" (key) values ($1) RETURNING id"#,
+            "-----BEGIN PUBLIC KEY-----",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        sqlx::query!(
+            r#"INSERT INTO "users" (vanity, username, email, password, public_keys) values ($1, $2, $3, $4, $5)"#,
+            "user",
+            "User",
+            "test@gravitalia.com",
+            "",
+            key,
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let mut config = status::Configuration::default();
+        config.url = "https://account.gravitalia.com/".into();
+        let state = AppState {
+            db: database::Database { postgres: pool },
+            config,
+            keys: jwt::KeyManager::default(),
+            mailer: std::sync::Arc::new(mail::Stdout),
+            captcha: None,
+        };
+        let app = app(state);
+
+        let request = |uri: &str, accept: &str| {
+            Request::builder()
+                .method(http::Method::GET)
+                .uri(uri)
+                .header(http::header::ACCEPT, accept)
+                .body(RequestBody::empty())
+                .unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(request("/users/user", "application/json"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let user: user::User = serde_json::from_slice(&body).unwrap();
+        assert_eq!(user.username, "User");
+
+        let response = app
+            .clone()
+            .oneshot(request("/users/user", "application/activity+json"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let person: router::users::Person = serde_json::from_slice(&body).unwrap();
+        assert_eq!(person.id, "https://account.gravitalia.com/users/user");
+        assert_eq!(person.inbox, "https://account.gravitalia.com/users/user/inbox");
+        assert!(person
+            .context
+            .contains(&"https://www.w3.org/ns/activitystreams".to_string()));
+        assert_eq!(
+            person.public_key.unwrap().public_key_pem,
+            "-----BEGIN PUBLIC KEY-----"
+        );
+
+        // Advertised collections exist.
+        for uri in [person.inbox, person.outbox] {
+            let path = url::Url::parse(&uri).unwrap().path().to_owned();
+            let response = app
+                .clone()
+                .oneshot(request(&path, "application/activity+json"))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            let collection: router::users::OrderedCollection =
+                serde_json::from_slice(&body).unwrap();
+            assert_eq!(collection.id, uri);
+        }
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/users/user/inbox")
+                    .body(RequestBody::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+
+        let response = app
+            .oneshot(request("/users/unknown", "application/json"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,533 @@
+//! WebAuthn registration and passwordless login ceremonies.
+
+use axum::{extract::State, http::StatusCode, response::Response as HttpResponse, Json};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rand::distributions::{Alphanumeric, DistString};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use validator::{Validate, ValidationError, ValidationErrors};
+
+use crate::webauthn::{self, ClientData, RelyingParty};
+use crate::{database::Database, status::Configuration, user::User};
+
+use super::{Authenticated, ServerError, Valid};
+
+const CHALLENGE_LENGTH: usize = 32;
+/// Ceremony timeout, in milliseconds.
+const TIMEOUT: u64 = 5 * 60 * 1000;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PublicKeyCredentialParameters {
+    r#type: String,
+    alg: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserEntity {
+    id: String,
+    name: String,
+    display_name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CredentialDescriptor {
+    r#type: String,
+    id: String,
+}
+
+/// Options of `navigator.credentials.create()`.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreationOptions {
+    pub challenge: String,
+    rp: RelyingParty,
+    user: UserEntity,
+    pub_key_cred_params: Vec<PublicKeyCredentialParameters>,
+    exclude_credentials: Vec<CredentialDescriptor>,
+    authenticator_selection: serde_json::Value,
+    attestation: String,
+    timeout: u64,
+}
+
+/// Options of `navigator.credentials.get()`.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RequestOptions {
+    pub challenge: String,
+    rp_id: String,
+    user_verification: String,
+    timeout: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AttestationResponse {
+    #[serde(rename = "clientDataJSON")]
+    client_data_json: String,
+    #[serde(rename = "attestationObject")]
+    attestation_object: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct RegistrationBody {
+    id: String,
+    response: AttestationResponse,
+    #[validate(length(max = 64, message = "Name must contain at most 64 characters."))]
+    name: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AssertionResponse {
+    #[serde(rename = "clientDataJSON")]
+    client_data_json: String,
+    #[serde(rename = "authenticatorData")]
+    authenticator_data: String,
+    signature: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct AuthenticationBody {
+    id: String,
+    response: AssertionResponse,
+}
+
+/// Registered credential.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Credential {
+    id: String,
+    name: Option<String>,
+}
+
+fn relying_party(config: &Configuration) -> Result<RelyingParty, ServerError> {
+    RelyingParty::new(config)
+        .ok_or_else(|| ServerError::Internal("server URL cannot be used as RP ID".into()))
+}
+
+/// Error returned when a ceremony fails.
+fn invalid_credential(err: webauthn::Error) -> ServerError {
+    tracing::debug!(error = %err, "webauthn ceremony failed");
+
+    let error = ValidationError::new("invalid_credential")
+        .with_message("Credential cannot be verified.".into());
+    let mut errors = ValidationErrors::new();
+    errors.add("credential", error);
+    errors.into()
+}
+
+async fn new_challenge(
+    db: &Database,
+    user: Option<&str>,
+    ceremony: &str,
+) -> Result<String, sqlx::Error> {
+    let challenge =
+        URL_SAFE_NO_PAD.encode(Alphanumeric.sample_string(&mut OsRng, CHALLENGE_LENGTH));
+
+    // Abandoned ceremonies would otherwise pile up.
+    sqlx::query!(r#"DELETE FROM "webauthn_challenges" WHERE expire_at <= NOW()"#)
+        .execute(&db.postgres)
+        .await?;
+    sqlx::query!(
+        r#"INSERT INTO "webauthn_challenges" (challenge, user_vanity, ceremony) values ($1, $2, $3)"#,
+        challenge,
+        user,
+        ceremony,
+    )
+    .execute(&db.postgres)
+    .await?;
+
+    Ok(challenge)
+}
+
+/// Start registration of a new credential for the authenticated user.
+pub async fn register_options(
+    State(db): State<Database>,
+    State(config): State<Configuration>,
+    Authenticated(user): Authenticated,
+) -> Result<Json<CreationOptions>, ServerError> {
+    let rp = relying_party(&config)?;
+
+    let exclude_credentials = sqlx::query_scalar!(
+        r#"SELECT id FROM "webauthn_credentials" WHERE user_vanity = $1"#,
+        user.vanity
+    )
+    .fetch_all(&db.postgres)
+    .await?
+    .into_iter()
+    .map(|id| CredentialDescriptor {
+        r#type: "public-key".into(),
+        id,
+    })
+    .collect();
+
+    Ok(Json(CreationOptions {
+        challenge: new_challenge(&db, Some(&user.vanity), "registration").await?,
+        rp,
+        user: UserEntity {
+            id: URL_SAFE_NO_PAD.encode(&user.vanity),
+            name: user.vanity,
+            display_name: user.username,
+        },
+        pub_key_cred_params: [webauthn::ES256, webauthn::EDDSA, webauthn::RS256]
+            .into_iter()
+            .map(|alg| PublicKeyCredentialParameters {
+                r#type: "public-key".into(),
+                alg,
+            })
+            .collect(),
+        exclude_credentials,
+        // Discoverable credentials allow login without typing an email.
+        authenticator_selection: serde_json::json!({
+            "residentKey": "required",
+            "userVerification": "required",
+        }),
+        attestation: "none".into(),
+        timeout: TIMEOUT,
+    }))
+}
+
+/// Complete registration of a credential.
+pub async fn register(
+    State(db): State<Database>,
+    State(config): State<Configuration>,
+    Authenticated(user): Authenticated,
+    Valid(body): Valid<RegistrationBody>,
+) -> Result<(StatusCode, Json<Credential>), ServerError> {
+    let rp = relying_party(&config)?;
+
+    let client_data = webauthn::decode(&body.response.client_data_json)
+        .and_then(|raw| ClientData::parse(&raw))
+        .map_err(invalid_credential)?;
+    let consumed = sqlx::query!(
+        r#"DELETE FROM "webauthn_challenges"
+        WHERE challenge = $1 AND user_vanity = $2 AND ceremony = 'registration' AND expire_at > NOW()"#,
+        client_data.challenge,
+        user.vanity,
+    )
+    .execute(&db.postgres)
+    .await?
+    .rows_affected();
+    if consumed != 1 {
+        return Err(ServerError::Unauthorized);
+    }
+
+    let credential = webauthn::decode(&body.response.attestation_object)
+        .and_then(|attestation| rp.register(&client_data, &attestation))
+        .map_err(invalid_credential)?;
+    let id = URL_SAFE_NO_PAD.encode(&credential.id);
+    if id != body.id.trim_end_matches('=') {
+        return Err(invalid_credential(webauthn::Error::CredentialId));
+    }
+
+    sqlx::query!(
+        r#"INSERT INTO "webauthn_credentials" (id, user_vanity, public_key, sign_count, name)
+        values ($1, $2, $3, $4, $5)"#,
+        id,
+        user.vanity,
+        credential.public_key,
+        credential.sign_count as i64,
+        body.name,
+    )
+    .execute(&db.postgres)
+    .await?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(Credential {
+            id,
+            name: body.name,
+        }),
+    ))
+}
+
+/// Start a passwordless login.
+pub async fn login_options(
+    State(db): State<Database>,
+    State(config): State<Configuration>,
+) -> Result<Json<RequestOptions>, ServerError> {
+    let rp = relying_party(&config)?;
+
+    Ok(Json(RequestOptions {
+        challenge: new_challenge(&db, None, "authentication").await?,
+        rp_id: rp.id,
+        user_verification: "required".into(),
+        timeout: TIMEOUT,
+    }))
+}
+
+/// Complete a passwordless login with a discoverable credential.
+pub async fn login(
+    State(db): State<Database>,
+    State(config): State<Configuration>,
+    Valid(body): Valid<AuthenticationBody>,
+) -> Result<HttpResponse, ServerError> {
+    let rp = relying_party(&config)?;
+
+    let client_data = webauthn::decode(&body.response.client_data_json)
+        .and_then(|raw| ClientData::parse(&raw))
+        .map_err(invalid_credential)?;
+    let consumed = sqlx::query!(
+        r#"DELETE FROM "webauthn_challenges"
+        WHERE challenge = $1 AND user_vanity IS NULL AND ceremony = 'authentication' AND expire_at > NOW()"#,
+        client_data.challenge,
+    )
+    .execute(&db.postgres)
+    .await?
+    .rows_affected();
+    if consumed != 1 {
+        return Err(ServerError::Unauthorized);
+    }
+
+    let credential = sqlx::query!(
+        r#"SELECT user_vanity, public_key, sign_count FROM "webauthn_credentials" WHERE id = $1"#,
+        body.id.trim_end_matches('='),
+    )
+    .fetch_optional(&db.postgres)
+    .await?
+    .ok_or(ServerError::Unauthorized)?;
+
+    let sign_count = webauthn::decode(&body.response.authenticator_data)
+        .and_then(|authenticator_data| {
+            rp.authenticate(
+                &client_data,
+                &authenticator_data,
+                &webauthn::decode(&body.response.signature)?,
+                &credential.public_key,
+                credential.sign_count as u32,
+            )
+        })
+        .map_err(invalid_credential)?;
+
+    sqlx::query!(
+        r#"UPDATE "webauthn_credentials" SET sign_count = $2, last_used_at = NOW() WHERE id = $1"#,
+        body.id.trim_end_matches('='),
+        sign_count as i64,
+    )
+    .execute(&db.postgres)
+    .await?;
+
+    let user = User::default()
+        .with_vanity(credential.user_vanity)
+        .get(&db.postgres)
+        .await?;
+    user.reactivate(&db.postgres)
+        .await
+        .map_err(|err| match err {
+            // Past the grace period, the account is as good as unknown.
+            sqlx::Error::RowNotFound => ServerError::Unauthorized,
+            err => err.into(),
+        })?;
+    let token = user.generate_token(&db.postgres).await?;
+
+    Ok(super::login::signed_in(user, token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+    use axum::{
+        body::Body as RequestBody,
+        http::{self, Request},
+    };
+    use ciborium::Value;
+    use http_body_util::BodyExt;
+    use p256::ecdsa::{signature::Signer, Signature, SigningKey};
+    use sha2::{Digest, Sha256};
+    use sqlx::{Pool, Postgres};
+    use tower::ServiceExt;
+
+    const ORIGIN: &str = "https://account.gravitalia.com";
+
+    /// Software authenticator holding a single ES256 credential.
+    struct Authenticator {
+        id: Vec<u8>,
+        key: SigningKey,
+        sign_count: u32,
+    }
+
+    impl Authenticator {
+        fn new() -> Self {
+            Self {
+                id: b"software-authenticator".to_vec(),
+                key: SigningKey::random(&mut OsRng),
+                sign_count: 0,
+            }
+        }
+
+        fn client_data(r#type: &str, challenge: &str) -> String {
+            let client_data = serde_json::json!({
+                "type": r#type,
+                "challenge": challenge,
+                "origin": ORIGIN,
+            });
+            URL_SAFE_NO_PAD.encode(client_data.to_string())
+        }
+
+        fn authenticator_data(&mut self, flags: u8) -> Vec<u8> {
+            self.sign_count += 1;
+            [
+                &Sha256::digest("account.gravitalia.com")[..],
+                &[flags],
+                &self.sign_count.to_be_bytes(),
+            ]
+            .concat()
+        }
+
+        fn create(&mut self, challenge: &str) -> serde_json::Value {
+            let point = self.key.verifying_key().to_encoded_point(false);
+            let public_key = Value::Map(vec![
+                (1.into(), 2.into()),
+                (3.into(), (-7).into()),
+                ((-1).into(), 1.into()),
+                ((-2).into(), Value::Bytes(point.x().unwrap().to_vec())),
+                ((-3).into(), Value::Bytes(point.y().unwrap().to_vec())),
+            ]);
+            let mut auth_data = self.authenticator_data(0x45);
+            auth_data.extend([0; 16]);
+            auth_data.extend((self.id.len() as u16).to_be_bytes());
+            auth_data.extend(&self.id);
+            ciborium::into_writer(&public_key, &mut auth_data).unwrap();
+
+            let mut attestation_object = Vec::new();
+            ciborium::into_writer(
+                &Value::Map(vec![
+                    ("fmt".into(), "none".into()),
+                    ("attStmt".into(), Value::Map(vec![])),
+                    ("authData".into(), Value::Bytes(auth_data)),
+                ]),
+                &mut attestation_object,
+            )
+            .unwrap();
+
+            serde_json::json!({
+                "id": URL_SAFE_NO_PAD.encode(&self.id),
+                "response": {
+                    "clientDataJSON": Self::client_data("webauthn.create", challenge),
+                    "attestationObject": URL_SAFE_NO_PAD.encode(attestation_object),
+                },
+                "name": "Software",
+            })
+        }
+
+        fn get(&mut self, challenge: &str) -> serde_json::Value {
+            let client_data = Self::client_data("webauthn.get", challenge);
+            let auth_data = self.authenticator_data(0x05);
+            let message = [
+                &auth_data[..],
+                &Sha256::digest(URL_SAFE_NO_PAD.decode(&client_data).unwrap()),
+            ]
+            .concat();
+            let signature: Signature = self.key.sign(&message);
+
+            serde_json::json!({
+                "id": URL_SAFE_NO_PAD.encode(&self.id),
+                "response": {
+                    "clientDataJSON": client_data,
+                    "authenticatorData": URL_SAFE_NO_PAD.encode(auth_data),
+                    "signature": URL_SAFE_NO_PAD.encode(signature.to_der()),
+                },
+            })
+        }
+    }
+
+    #[sqlx::test]
+    async fn test_webauthn_ceremonies(pool: Pool<Postgres>) {
+        sqlx::query!(
+            r#"INSERT INTO "users" (vanity, username, email, password) values ($1, $2, $3, $4)"#,
+            "user",
+            "User",
+            "test@gravitalia.com",
+            "",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let token = User::default()
+            .with_vanity("user".into())
+            .get(&pool)
+            .await
+            .unwrap()
+            .generate_token(&pool)
+            .await
+            .unwrap();
+
+        let mut config = status::Configuration::default();
+        config.url = format!("{}/", ORIGIN);
+        let state = AppState {
+            db: database::Database { postgres: pool },
+            config,
+            keys: jwt::KeyManager::default(),
+            mailer: std::sync::Arc::new(mail::Stdout),
+            captcha: None,
+        };
+        let app = app(state);
+
+        let request = |uri: &str, token: Option<&str>, body: serde_json::Value| {
+            let mut request = Request::builder()
+                .method(http::Method::POST)
+                .uri(uri)
+                .header(http::header::CONTENT_TYPE, "application/json");
+            if let Some(token) = token {
+                request = request.header(http::header::AUTHORIZATION, format!("Bearer {}", token));
+            }
+            request.body(RequestBody::from(body.to_string())).unwrap()
+        };
+        let mut authenticator = Authenticator::new();
+
+        let response = app
+            .clone()
+            .oneshot(request(
+                "/users/@me/webauthn/register/options",
+                Some(&token),
+                serde_json::json!({}),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let options: CreationOptions = serde_json::from_slice(&body).unwrap();
+        assert_eq!(options.rp.id, "account.gravitalia.com");
+
+        let response = app
+            .clone()
+            .oneshot(request(
+                "/users/@me/webauthn/register",
+                Some(&token),
+                authenticator.create(&options.challenge),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let response = app
+            .clone()
+            .oneshot(request(
+                "/login/webauthn/options",
+                None,
+                serde_json::json!({}),
+            ))
+            .await
+            .unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let options: RequestOptions = serde_json::from_slice(&body).unwrap();
+
+        let assertion = authenticator.get(&options.challenge);
+        let response = app
+            .clone()
+            .oneshot(request("/login/webauthn", None, assertion.clone()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: router::login::Response = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body.user.vanity, "user");
+
+        // Challenges are single-use.
+        let response = app
+            .oneshot(request("/login/webauthn", None, assertion))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+}
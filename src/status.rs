@@ -8,7 +8,7 @@ use url::Url;
 use std::fs::File;
 use std::path::{Path, PathBuf};
 
-use crate::AppState;
+use crate::{captcha::Provider, ratelimit::Limits, AppState};
 
 const DEFAULT_STATUS_PATH: &str = "status.json";
 const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -27,15 +27,37 @@ pub enum Error {
 /// Structure of the `status.json` file.
 #[derive(Serialize, Deserialize, Default, Debug, Clone)]
 pub struct Configuration {
-    name: String,
+    pub name: String,
     pub url: String,
     favicon: Option<String>,
-    terms_of_service: String,
-    privacy_policy: String,
+    pub terms_of_service: String,
+    pub privacy_policy: String,
     #[serde(skip_deserializing)]
     version: String,
-    invite_only: bool,
+    pub invite_only: bool,
     background: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub captcha: Option<Captcha>,
+    /// Page signing users in, then sending them back to its `redirect` parameter.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub login_url: Option<String>,
+    /// Page asking users to approve a client, then posting to `/oauth/authorize`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub consent_url: Option<String>,
+    #[serde(default, skip_serializing)]
+    pub rate_limits: Limits,
+}
+
+/// Captcha shown on sign-up, and on login after repeated failures.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Captcha {
+    pub provider: Provider,
+    /// Public key of third-party providers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub site_key: Option<String>,
+    /// Leading zero bits required by proof of work.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub difficulty: Option<u32>,
 }
 
 impl FromRef<AppState> for Configuration {
@@ -62,6 +84,8 @@ impl Configuration {
                 config.terms_of_service = normalize_url(&config.terms_of_service)?;
                 config.privacy_policy = normalize_url(&config.privacy_policy)?;
                 config.background = config.background.map(|b| normalize_url(&b)).transpose()?;
+                config.login_url = config.login_url.map(|l| normalize_url(&l)).transpose()?;
+                config.consent_url = config.consent_url.map(|c| normalize_url(&c)).transpose()?;
 
                 Ok(config)
             }
@@ -1,9 +1,21 @@
 use rand::distributions::{Alphanumeric, DistString};
 use rand::rngs::OsRng;
 use serde::{Deserialize, Serialize};
-use sqlx::{Pool, Postgres};
+use sqlx::{PgConnection, PgExecutor, Pool, Postgres};
+
+use std::time::Duration;
 
 const TOKEN_LENGTH: usize = 64;
+/// Lifetime of tokens delegated to third-party clients, in seconds.
+pub const CLIENT_TOKEN_LIFETIME: i64 = 60 * 60;
+/// `flags` bit set once the user proved owning their email address.
+pub const FLAG_VERIFIED_EMAIL: i32 = 1 << 0;
+/// `flags` bit of administrators, granted directly in the database.
+pub const FLAG_ADMIN: i32 = 1 << 1;
+/// Days a deleted account can still be reactivated by logging in.
+pub const DELETION_GRACE_DAYS: i32 = 30;
+/// Days before a suspended account is deleted.
+pub const SUSPENSION_GRACE_DAYS: i32 = 15;
 
 /// Database user representation.
 #[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
@@ -56,6 +68,22 @@ impl User {
         }
     }
 
+    /// Get the user owning a valid first-party token.
+    ///
+    /// Tokens delegated to third-party clients, and those of suspended or deleted users, are rejected.
+    pub async fn from_token(conn: &Pool<Postgres>, token: &str) -> Result<Self, sqlx::Error> {
+        sqlx::query_as!(
+            User,
+            r#"SELECT u.vanity, u.username, u.email, u.avatar, u.flags, u.password
+            FROM tokens t JOIN users u ON u.vanity = t.user_vanity
+            WHERE t.token = $1 AND t.client_id IS NULL AND t.expire_at > NOW()
+            AND u.suspended_at IS NULL AND u.deleted_at IS NULL"#,
+            token,
+        )
+        .fetch_one(conn)
+        .await
+    }
+
     /// Generate a token for this specific user.
     pub async fn generate_token(&self, conn: &Pool<Postgres>) -> Result<String, sqlx::Error> {
         if self.vanity.is_empty() {
@@ -76,4 +104,119 @@ impl User {
 
         Ok(token)
     }
+
+    /// Generate a short-lived token delegated to a third-party client, restricted to `scope`.
+    ///
+    /// `family` links the token to the refresh token it was issued with.
+    pub async fn generate_client_token<'c>(
+        &self,
+        conn: impl PgExecutor<'c>,
+        client_id: &str,
+        scope: &str,
+        family: &str,
+    ) -> Result<String, sqlx::Error> {
+        if self.vanity.is_empty() {
+            return Err(sqlx::Error::ColumnNotFound(
+                "Missing column 'vanity' column".into(),
+            ));
+        }
+
+        let token = Alphanumeric.sample_string(&mut OsRng, TOKEN_LENGTH);
+
+        sqlx::query!(
+            r#"INSERT INTO "tokens" (token, user_vanity, client_id, scope, family, expire_at)
+            values ($1, $2, $3, $4, $5, NOW() + make_interval(secs => $6))"#,
+            token,
+            self.vanity,
+            client_id,
+            scope,
+            family,
+            CLIENT_TOKEN_LIFETIME as f64,
+        )
+        .execute(conn)
+        .await?;
+
+        Ok(token)
+    }
+
+    /// Revoke every token of this user, first-party and delegated ones, but `keep`.
+    pub async fn revoke_tokens(
+        &self,
+        conn: &mut PgConnection,
+        keep: Option<&str>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"DELETE FROM "tokens" WHERE user_vanity = $1 AND token IS DISTINCT FROM $2"#,
+            self.vanity,
+            keep,
+        )
+        .execute(&mut *conn)
+        .await?;
+        sqlx::query!(
+            r#"DELETE FROM "refresh_tokens" WHERE user_vanity = $1"#,
+            self.vanity
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Cancel a pending deletion.
+    ///
+    /// Fails with [`sqlx::Error::RowNotFound`] once the grace period is over.
+    pub async fn reactivate(&self, conn: &Pool<Postgres>) -> Result<(), sqlx::Error> {
+        let expired = sqlx::query_scalar!(
+            r#"SELECT deleted_at + $2::INT < CURRENT_DATE AS "expired!" FROM "users"
+            WHERE vanity = $1 AND deleted_at IS NOT NULL"#,
+            self.vanity,
+            DELETION_GRACE_DAYS,
+        )
+        .fetch_optional(conn)
+        .await?;
+
+        match expired {
+            None => Ok(()),
+            Some(true) => Err(sqlx::Error::RowNotFound),
+            Some(false) => {
+                sqlx::query!(
+                    r#"UPDATE "users" SET deleted_at = NULL WHERE vanity = $1"#,
+                    self.vanity
+                )
+                .execute(conn)
+                .await?;
+
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Hard-delete accounts whose deletion or suspension grace period is over.
+pub async fn purge(conn: &Pool<Postgres>) -> Result<u64, sqlx::Error> {
+    Ok(sqlx::query!(
+        r#"DELETE FROM "users"
+        WHERE (deleted_at IS NOT NULL AND deleted_at + $1::INT < CURRENT_DATE)
+           OR (suspended_at IS NOT NULL AND suspended_at + $2::INT < CURRENT_DATE)"#,
+        DELETION_GRACE_DAYS,
+        SUSPENSION_GRACE_DAYS,
+    )
+    .execute(conn)
+    .await?
+    .rows_affected())
+}
+
+/// Run [`purge`] every `period`, as `pg_cron` may not be installed.
+pub async fn purge_periodically(conn: Pool<Postgres>, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+
+    loop {
+        interval.tick().await;
+
+        match purge(&conn).await {
+            Ok(0) => {}
+            Ok(count) => tracing::info!(count, "purged expired accounts"),
+            Err(err) => tracing::error!(error = %err, "failed to purge expired accounts"),
+        }
+    }
 }
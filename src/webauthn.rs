@@ -0,0 +1,329 @@
+//! WebAuthn (Level 2 <https://www.w3.org/TR/webauthn-2/>) relying party.
+//!
+//! Credentials are requested with `none` attestation conveyance, so attestation
+//! statements are not verified.
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use ciborium::Value;
+use rsa::pkcs1v15;
+use rsa::signature::Verifier;
+use rsa::{BigUint, RsaPublicKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use crate::status::Configuration;
+
+/// User presence flag of authenticator data.
+const FLAG_UP: u8 = 0x01;
+/// User verification flag of authenticator data.
+const FLAG_UV: u8 = 0x04;
+/// Attested credential data flag of authenticator data.
+const FLAG_AT: u8 = 0x40;
+
+/// COSE algorithm identifiers (RFC 9053) accepted for credentials, by order of preference.
+pub const ES256: i64 = -7;
+pub const EDDSA: i64 = -8;
+pub const RS256: i64 = -257;
+
+/// Errors that may occur during a ceremony.
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Invalid base64url encoding: {0}")]
+    Base64(#[from] base64::DecodeError),
+    #[error("Invalid client data: {0}")]
+    ClientData(#[from] serde_json::Error),
+    #[error("Invalid CBOR: {0}")]
+    Cbor(String),
+    #[error("Unexpected ceremony type")]
+    Type,
+    #[error("Origin mismatch")]
+    Origin,
+    #[error("Malformed authenticator data")]
+    AuthenticatorData,
+    #[error("RP ID hash mismatch")]
+    RpId,
+    #[error("User presence is required")]
+    UserPresence,
+    #[error("User verification is required")]
+    UserVerification,
+    #[error("Credential ID mismatch")]
+    CredentialId,
+    #[error("Unsupported public key")]
+    UnsupportedKey,
+    #[error("Invalid signature")]
+    Signature,
+    #[error("Signature counter did not increase")]
+    Counter,
+}
+
+/// Decode a base64url value as sent by WebAuthn clients.
+pub fn decode(value: &str) -> Result<Vec<u8>, Error> {
+    Ok(URL_SAFE_NO_PAD.decode(value.trim_end_matches('='))?)
+}
+
+/// Collected client data (WebAuthn section 5.8.1).
+#[derive(Debug, Deserialize)]
+pub struct ClientData {
+    r#type: String,
+    pub challenge: String,
+    origin: String,
+    #[serde(skip)]
+    hash: Vec<u8>,
+}
+
+impl ClientData {
+    /// Parse `clientDataJSON`, keeping its hash for signature verification.
+    pub fn parse(raw: &[u8]) -> Result<Self, Error> {
+        let mut client_data: ClientData = serde_json::from_slice(raw)?;
+        client_data.hash = Sha256::digest(raw).to_vec();
+        Ok(client_data)
+    }
+}
+
+/// Public key of a credential.
+pub enum PublicKey {
+    Es256(p256::ecdsa::VerifyingKey),
+    EdDsa(ed25519_dalek::VerifyingKey),
+    Rs256(RsaPublicKey),
+}
+
+impl PublicKey {
+    /// Decode a `COSE_Key` (RFC 9052 section 7).
+    pub fn from_cose(bytes: &[u8]) -> Result<Self, Error> {
+        let value: Value =
+            ciborium::from_reader(bytes).map_err(|err| Error::Cbor(err.to_string()))?;
+        Self::from_value(&value)
+    }
+
+    fn from_value(value: &Value) -> Result<Self, Error> {
+        let map = value.as_map().ok_or(Error::UnsupportedKey)?;
+        let get = |label: i128| {
+            map.iter()
+                .find(|(key, _)| key.as_integer().map(i128::from) == Some(label))
+                .map(|(_, value)| value)
+        };
+        let int = |label| get(label).and_then(Value::as_integer).map(i128::from);
+        let bytes = |label| {
+            get(label)
+                .and_then(Value::as_bytes)
+                .map(Vec::as_slice)
+                .ok_or(Error::UnsupportedKey)
+        };
+
+        // kty (1), alg (3) and crv (-1).
+        match (int(1), int(3).map(|alg| alg as i64), int(-1)) {
+            (Some(2), Some(ES256), Some(1)) => {
+                let (x, y) = (bytes(-2)?, bytes(-3)?);
+                if x.len() != 32 || y.len() != 32 {
+                    return Err(Error::UnsupportedKey);
+                }
+                let point = p256::EncodedPoint::from_affine_coordinates(x.into(), y.into(), false);
+                p256::ecdsa::VerifyingKey::from_encoded_point(&point)
+                    .map(PublicKey::Es256)
+                    .map_err(|_| Error::UnsupportedKey)
+            }
+            (Some(1), Some(EDDSA), Some(6)) => {
+                let x = bytes(-2)?.try_into().map_err(|_| Error::UnsupportedKey)?;
+                ed25519_dalek::VerifyingKey::from_bytes(x)
+                    .map(PublicKey::EdDsa)
+                    .map_err(|_| Error::UnsupportedKey)
+            }
+            (Some(3), Some(RS256), _) => RsaPublicKey::new(
+                BigUint::from_bytes_be(bytes(-1)?),
+                BigUint::from_bytes_be(bytes(-2)?),
+            )
+            .map(PublicKey::Rs256)
+            .map_err(|_| Error::UnsupportedKey),
+            _ => Err(Error::UnsupportedKey),
+        }
+    }
+
+    /// Verify an assertion signature.
+    pub fn verify(&self, message: &[u8], signature: &[u8]) -> Result<(), Error> {
+        let valid = match self {
+            PublicKey::Es256(key) => p256::ecdsa::Signature::from_der(signature)
+                .is_ok_and(|signature| key.verify(message, &signature).is_ok()),
+            PublicKey::EdDsa(key) => ed25519_dalek::Signature::from_slice(signature)
+                .is_ok_and(|signature| key.verify(message, &signature).is_ok()),
+            PublicKey::Rs256(key) => {
+                pkcs1v15::Signature::try_from(signature).is_ok_and(|signature| {
+                    pkcs1v15::VerifyingKey::<Sha256>::new(key.clone())
+                        .verify(message, &signature)
+                        .is_ok()
+                })
+            }
+        };
+
+        valid.then_some(()).ok_or(Error::Signature)
+    }
+}
+
+/// Authenticator data (WebAuthn section 6.1).
+struct AuthenticatorData {
+    flags: u8,
+    sign_count: u32,
+    credential: Option<(Vec<u8>, Value)>,
+}
+
+impl AuthenticatorData {
+    fn parse(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < 37 {
+            return Err(Error::AuthenticatorData);
+        }
+
+        let flags = bytes[32];
+        let sign_count = u32::from_be_bytes([bytes[33], bytes[34], bytes[35], bytes[36]]);
+
+        let credential = if flags & FLAG_AT != 0 {
+            // AAGUID is ignored, then comes credential ID length and value.
+            let rest = bytes.get(55..).ok_or(Error::AuthenticatorData)?;
+            let length = u16::from_be_bytes([bytes[53], bytes[54]]) as usize;
+            if rest.len() < length {
+                return Err(Error::AuthenticatorData);
+            }
+            let (id, mut public_key) = rest.split_at(length);
+            let public_key: Value = ciborium::from_reader(&mut public_key)
+                .map_err(|err| Error::Cbor(err.to_string()))?;
+
+            Some((id.to_vec(), public_key))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            flags,
+            sign_count,
+            credential,
+        })
+    }
+}
+
+/// Credential created by a registration ceremony.
+pub struct Credential {
+    /// Credential ID.
+    pub id: Vec<u8>,
+    /// Encoded `COSE_Key`.
+    pub public_key: Vec<u8>,
+    pub sign_count: u32,
+}
+
+/// Relying party identity (WebAuthn section 5.4.2).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RelyingParty {
+    pub id: String,
+    pub name: String,
+    #[serde(skip)]
+    origin: String,
+}
+
+impl RelyingParty {
+    /// Derive the relying party from the server URL: the RP ID is its host.
+    pub fn new(config: &Configuration) -> Option<Self> {
+        let url = Url::parse(&config.url).ok()?;
+        let id = url.host_str()?.to_owned();
+
+        Some(Self {
+            name: if config.name.is_empty() {
+                id.clone()
+            } else {
+                config.name.clone()
+            },
+            origin: url.origin().ascii_serialization(),
+            id,
+        })
+    }
+
+    fn check_client_data(&self, client_data: &ClientData, r#type: &str) -> Result<(), Error> {
+        if client_data.r#type != r#type {
+            return Err(Error::Type);
+        }
+        if client_data.origin != self.origin {
+            return Err(Error::Origin);
+        }
+
+        Ok(())
+    }
+
+    /// Credentials replace passwords, so user verification is always required.
+    fn check_authenticator_data(
+        &self,
+        authenticator_data: &[u8],
+    ) -> Result<AuthenticatorData, Error> {
+        let data = AuthenticatorData::parse(authenticator_data)?;
+
+        if authenticator_data[..32] != Sha256::digest(self.id.as_bytes())[..] {
+            return Err(Error::RpId);
+        }
+        if data.flags & FLAG_UP == 0 {
+            return Err(Error::UserPresence);
+        }
+        if data.flags & FLAG_UV == 0 {
+            return Err(Error::UserVerification);
+        }
+
+        Ok(data)
+    }
+
+    /// Verify a registration ceremony (WebAuthn section 7.1).
+    ///
+    /// The challenge of `client_data` must be checked by the caller.
+    pub fn register(
+        &self,
+        client_data: &ClientData,
+        attestation_object: &[u8],
+    ) -> Result<Credential, Error> {
+        self.check_client_data(client_data, "webauthn.create")?;
+
+        let attestation: Value = ciborium::from_reader(attestation_object)
+            .map_err(|err| Error::Cbor(err.to_string()))?;
+        let auth_data = attestation
+            .as_map()
+            .and_then(|map| {
+                map.iter()
+                    .find(|(key, _)| key.as_text() == Some("authData"))
+                    .and_then(|(_, value)| value.as_bytes())
+            })
+            .ok_or(Error::AuthenticatorData)?;
+        let data = self.check_authenticator_data(auth_data)?;
+        let (id, public_key) = data.credential.ok_or(Error::AuthenticatorData)?;
+
+        // Reject keys we would not be able to verify later.
+        PublicKey::from_value(&public_key)?;
+        let mut encoded = Vec::new();
+        ciborium::into_writer(&public_key, &mut encoded)
+            .map_err(|err| Error::Cbor(err.to_string()))?;
+
+        Ok(Credential {
+            id,
+            public_key: encoded,
+            sign_count: data.sign_count,
+        })
+    }
+
+    /// Verify an authentication ceremony (WebAuthn section 7.2), returning the new signature counter.
+    ///
+    /// The challenge of `client_data` must be checked by the caller.
+    pub fn authenticate(
+        &self,
+        client_data: &ClientData,
+        authenticator_data: &[u8],
+        signature: &[u8],
+        public_key: &[u8],
+        sign_count: u32,
+    ) -> Result<u32, Error> {
+        self.check_client_data(client_data, "webauthn.get")?;
+        let data = self.check_authenticator_data(authenticator_data)?;
+
+        let message = [authenticator_data, &client_data.hash].concat();
+        PublicKey::from_cose(public_key)?.verify(&message, signature)?;
+
+        // A counter going backwards reveals a cloned authenticator.
+        if (data.sign_count != 0 || sign_count != 0) && data.sign_count <= sign_count {
+            return Err(Error::Counter);
+        }
+
+        Ok(data.sign_count)
+    }
+}
@@ -0,0 +1,61 @@
+//! Based on JSON Web Key Set (RFC 7517 <https://datatracker.ietf.org/doc/html/rfc7517#section-5>).
+//!
+//! Path: /.well-known/jwks.json
+
+use axum::{extract::State, Json};
+
+use crate::jwt::{Jwks, KeyManager};
+
+/// Public keys used to verify ID Tokens.
+pub async fn handler(State(keys): State<KeyManager>) -> Json<Jwks> {
+    Json(keys.jwks())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+    use axum::{
+        body::Body as RequestBody,
+        http::{self, Request, StatusCode},
+    };
+    use http_body_util::BodyExt;
+    use sqlx::{Pool, Postgres};
+    use tower::ServiceExt;
+
+    #[sqlx::test]
+    async fn test_jwks_handler(pool: Pool<Postgres>) {
+        let state = AppState {
+            keys: jwt::KeyManager::new(&pool).await.unwrap(),
+            mailer: std::sync::Arc::new(mail::Stdout),
+            captcha: None,
+            db: database::Database { postgres: pool },
+            config: status::Configuration::default(),
+        };
+        let app = app(state);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::GET)
+                    .uri("/.well-known/jwks.json")
+                    .body(RequestBody::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Jwks = serde_json::from_slice(&body).unwrap();
+        assert!(body
+            .keys
+            .iter()
+            .any(|key| key.alg == "RS256" && key.n.is_some()));
+        assert!(body
+            .keys
+            .iter()
+            .any(|key| key.alg == "EdDSA" && key.x.is_some()));
+    }
+}
@@ -1,3 +1,5 @@
+pub mod jwks;
+pub mod openid_configuration;
 pub mod webfinger;
 
 use axum::routing::get;
@@ -8,5 +10,7 @@ use crate::AppState;
 pub fn well_known(state: AppState) -> Router {
     Router::new()
         .route("/webfinger", get(webfinger::handler))
+        .route("/jwks.json", get(jwks::handler))
+        .route("/openid-configuration", get(openid_configuration::handler))
         .with_state(state)
 }
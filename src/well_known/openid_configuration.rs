@@ -0,0 +1,135 @@
+//! Based on OpenID Connect Discovery 1.0 <https://openid.net/specs/openid-connect-discovery-1_0.html>.
+//!
+//! Path: /.well-known/openid-configuration
+
+use axum::http::StatusCode;
+use axum::{extract::State, Json};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::jwt::Algorithm;
+use crate::status::Configuration;
+
+/// OpenID Provider metadata.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Metadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: String,
+    introspection_endpoint: String,
+    revocation_endpoint: String,
+    jwks_uri: String,
+    registration_endpoint: String,
+    scopes_supported: Vec<String>,
+    response_types_supported: Vec<String>,
+    grant_types_supported: Vec<String>,
+    subject_types_supported: Vec<String>,
+    id_token_signing_alg_values_supported: Vec<String>,
+    token_endpoint_auth_methods_supported: Vec<String>,
+    code_challenge_methods_supported: Vec<String>,
+    claims_supported: Vec<String>,
+    op_policy_uri: String,
+    op_tos_uri: String,
+}
+
+impl TryFrom<&Configuration> for Metadata {
+    type Error = url::ParseError;
+
+    fn try_from(config: &Configuration) -> Result<Self, Self::Error> {
+        let url = Url::parse(&config.url)?;
+        let strings = |values: &[&str]| values.iter().map(|v| v.to_string()).collect();
+
+        Ok(Self {
+            issuer: config.url.clone(),
+            authorization_endpoint: url.join("oauth/authorize")?.to_string(),
+            token_endpoint: url.join("oauth/token")?.to_string(),
+            userinfo_endpoint: url.join("oauth/userinfo")?.to_string(),
+            introspection_endpoint: url.join("oauth/introspect")?.to_string(),
+            revocation_endpoint: url.join("oauth/revoke")?.to_string(),
+            jwks_uri: url.join(".well-known/jwks.json")?.to_string(),
+            registration_endpoint: url.join("oauth/register")?.to_string(),
+            scopes_supported: strings(crate::oauth::SCOPES),
+            response_types_supported: strings(&["code"]),
+            grant_types_supported: strings(&["authorization_code", "refresh_token"]),
+            subject_types_supported: strings(&["public"]),
+            id_token_signing_alg_values_supported: Algorithm::ALL
+                .iter()
+                .map(|alg| alg.as_str().to_owned())
+                .collect(),
+            token_endpoint_auth_methods_supported: strings(&[
+                "none",
+                "client_secret_basic",
+                "client_secret_post",
+            ]),
+            code_challenge_methods_supported: strings(&["S256"]),
+            claims_supported: strings(&[
+                "iss",
+                "sub",
+                "aud",
+                "exp",
+                "iat",
+                "nonce",
+                "preferred_username",
+                "picture",
+                "email",
+            ]),
+            op_policy_uri: config.privacy_policy.clone(),
+            op_tos_uri: config.terms_of_service.clone(),
+        })
+    }
+}
+
+pub async fn handler(State(config): State<Configuration>) -> Result<Json<Metadata>, StatusCode> {
+    Metadata::try_from(&config)
+        .map(Json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+    use axum::{
+        body::Body as RequestBody,
+        http::{self, Request, StatusCode},
+    };
+    use http_body_util::BodyExt;
+    use sqlx::{Pool, Postgres};
+    use tower::ServiceExt;
+
+    #[sqlx::test]
+    async fn test_openid_configuration_handler(pool: Pool<Postgres>) {
+        let mut config = status::Configuration::default();
+        config.url = "https://auth.gravitalia.com/".into();
+        let state = AppState {
+            db: database::Database { postgres: pool },
+            config,
+            keys: jwt::KeyManager::default(),
+            mailer: std::sync::Arc::new(mail::Stdout),
+            captcha: None,
+        };
+        let app = app(state);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::GET)
+                    .uri("/.well-known/openid-configuration")
+                    .body(RequestBody::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Metadata = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body.issuer, "https://auth.gravitalia.com/");
+        assert_eq!(
+            body.token_endpoint,
+            "https://auth.gravitalia.com/oauth/token"
+        );
+    }
+}